@@ -0,0 +1,53 @@
+//! 比较逐条解压record和按block解压record的耗时, 即indexing时遍历全部释义的耗时
+//! 默认使用 MdxWriter 生成的词典, 也可以指定真实的词典:
+//! MDX_BENCH_FILE=./resources/mdx/en/牛津高阶8.mdx cargo bench --bench items
+use std::env;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use mdict_rs::mdict::{Compression, Mdx, MdxWriter};
+
+/// 10000个词, 每个释义几百bytes的html, 默认block大小和zlib压缩
+fn synthetic_mdx() -> Vec<u8> {
+    let entries = (0..10000).map(|i| {
+        let word = format!("word{:05}", i);
+        let definition = format!(
+            "<div class=\"entry\"><b>{}</b> <i>n.</i> {}</div>",
+            word,
+            format!("definition {} of {}; ", i % 7, word).repeat(i % 10 + 5)
+        );
+        (word, definition)
+    });
+    let mut data = vec![];
+    MdxWriter::new("bench")
+        .compression(Compression::Zlib)
+        .write(entries, &mut data)
+        .expect("write mdx");
+    data
+}
+
+fn bench_items(c: &mut Criterion) {
+    let data = match env::var("MDX_BENCH_FILE") {
+        Ok(file) => std::fs::read(&file).unwrap_or_else(|e| panic!("read {}: {}", file, e)),
+        Err(_) => synthetic_mdx(),
+    };
+    let mdx = Mdx::new(&data).expect("parse mdx");
+
+    let mut group = c.benchmark_group("items");
+    group.sample_size(10);
+    // 原来的做法: 每个record都解压一次所在的block
+    mdx.set_cache_capacity(0);
+    group.bench_function("per_record", |b| {
+        b.iter(|| {
+            mdx.entries()
+                .map(|rs| mdx.definition(rs).unwrap().len())
+                .sum::<usize>()
+        })
+    });
+    group.bench_function("per_block", |b| {
+        b.iter(|| mdx.items().map(|r| r.unwrap().definition().len()).sum::<usize>())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_items);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mdict_rs::mdict::Mdd;
+
+// 任何输入都不能panic, 解析成功时读取所有资源
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mdd) = Mdd::new(data) {
+        for rs in mdd.entries() {
+            let _ = mdd.get(rs.text());
+        }
+    }
+});
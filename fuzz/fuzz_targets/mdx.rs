@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mdict_rs::mdict::Mdx;
+
+// 任何输入都不能panic, 解析成功时遍历所有record并查词
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mdx) = Mdx::new(data) {
+        for record in mdx.items() {
+            let _ = record;
+        }
+        let _ = mdx.verify();
+        if let Some(rs) = mdx.entries().next() {
+            let _ = mdx.resolve(rs.text());
+        }
+    }
+});
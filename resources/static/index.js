@@ -50,6 +50,14 @@ $(document).keydown(function (e) {
 $(document).on('click', 'a', function (e) {
     console.log($(this).attr('href'));
     let href = $(this).attr('href');// '/cool'
+    // /res/ 是词典mdd中的资源, 发音在页面中直接播放
+    if (href.startsWith('/res/')) {
+        if (/\.(mp3|wav|ogg|spx)$/i.test(href)) {
+            new Audio(href).play();
+            e.preventDefault()
+        }
+        return;
+    }
     if (href.startsWith('/') && !href.startsWith('/#')) {
         $('#word').val(href.slice(1)) // 'cool'
         postQuery();
@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use mdict_rs::mdict::{Compression, MddWriter, Mdx, Passcode, DEFAULT_BLOCK_SIZE, DEFAULT_VOLUME_SIZE};
+
+/// mdict 命令行工具
+#[derive(Parser)]
+#[command(name = "mdict-cli")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 检查mdx文件所有block的checksum, 大小和key的顺序, 有问题时返回1
+    Verify {
+        file: PathBuf,
+        /// 加密词典的注册码文件, 第一行是regcode, 第二行是email或device id
+        /// 默认使用mdx旁边同名的 .key 文件
+        #[arg(long)]
+        key: Option<PathBuf>,
+        /// 以json输出检查结果
+        #[arg(long)]
+        json: bool,
+    },
+    /// 把目录下的图片, 音频, css等资源文件打包成mdd, 太大时分卷写到 .1.mdd .2.mdd 中
+    Pack {
+        dir: PathBuf,
+        mdd: PathBuf,
+        /// 默认使用mdd的文件名
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(long, value_enum, default_value_t = CompressionArg::Zlib)]
+        compression: CompressionArg,
+        /// 每个block解压后的大小
+        #[arg(long, default_value_t = DEFAULT_BLOCK_SIZE)]
+        block_size: usize,
+        /// 每个分卷中资源的总大小
+        #[arg(long, default_value_t = DEFAULT_VOLUME_SIZE)]
+        volume_size: u64,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CompressionArg {
+    None,
+    Lzo,
+    Zlib,
+}
+
+impl From<CompressionArg> for Compression {
+    fn from(arg: CompressionArg) -> Compression {
+        match arg {
+            CompressionArg::None => Compression::None,
+            CompressionArg::Lzo => Compression::Lzo,
+            CompressionArg::Zlib => Compression::Zlib,
+        }
+    }
+}
+
+fn main() -> anyhow::Result<ExitCode> {
+    pretty_env_logger::init();
+    match Cli::parse().command {
+        Command::Verify { file, key, json } => {
+            let key = key.or_else(|| Some(file.with_extension("key")).filter(|key| key.exists()));
+            let passcode = key.map(|key| Passcode::from_key_file(key, None)).transpose()?;
+            let report = Mdx::verify_file(&file, passcode.as_ref())?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{}", report);
+            }
+            Ok(if report.is_ok() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+        }
+        Command::Pack {
+            dir,
+            mdd,
+            title,
+            compression,
+            block_size,
+            volume_size,
+        } => {
+            let title = title.unwrap_or_else(|| {
+                mdd.file_stem()
+                    .map(|s| s.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            });
+            let volumes = MddWriter::new(&title)
+                .compression(compression.into())
+                .block_size(block_size)
+                .volume_size(volume_size)
+                .write_dir(&dir, &mdd)?;
+            for volume in volumes {
+                println!("{}", volume.display());
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
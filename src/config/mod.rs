@@ -1,15 +1,92 @@
 use std::env;
 use std::path::PathBuf;
 
+use log::error;
+
+use mdict_rs::mdict::{volume_path, Passcode};
+
 pub const MDX_FILES: &[&str] = &[
     "./resources/mdx/en/牛津高阶8.mdx",
     "./resources/mdx/en/朗文当代4.mdx",
     // "./resources/mdx/en/简明必应.mdx",
 ];
 
+/// mdx对应的mdd资源文件, 和mdx同名, 例如 牛津高阶8.mdx -> 牛津高阶8.mdd
+/// 分卷的mdd还有 牛津高阶8.1.mdd 牛津高阶8.2.mdd ..., 只返回存在的文件
+pub fn mdd_paths(mdx_file: &str) -> Vec<PathBuf> {
+    let mdd = PathBuf::from(mdx_file).with_extension("mdd");
+    (0..)
+        .map(|n| volume_path(&mdd, n))
+        .take_while(|path| path.exists())
+        .collect()
+}
+
+/// Encrypted="1" 的词典需要注册信息: 和mdx同名的 .key 文件, 例如 牛津高阶8.key
+/// 第一行是注册码, 第二行是注册时使用的email或device id
+/// .key 文件读取失败或者注册码不对时只记录错误, 和没有 .key 文件一样
+pub fn passcode(mdx_file: &str) -> Option<Passcode> {
+    let path = PathBuf::from(mdx_file).with_extension("key");
+    if !path.exists() {
+        return None;
+    }
+    match Passcode::from_key_file(&path, None) {
+        Ok(passcode) => Some(passcode),
+        Err(e) => {
+            error!("read key file {:?} failed: {}", &path, e);
+            None
+        }
+    }
+}
+
+/// 词典名称, 取mdx的文件名, 用于资源路由 /res/{dict}/{path}
+pub fn dict_name(mdx_file: &str) -> String {
+    PathBuf::from(mdx_file)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
 
 pub fn static_path() -> anyhow::Result<PathBuf> {
     let mut path: PathBuf = env!("CARGO_MANIFEST_DIR").into();
     path.push("resources/static");
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use mdict_rs::mdict::{Mdd, MddWriter};
+
+    use super::*;
+
+    #[test]
+    fn find_volumes_written_by_pack() {
+        let dir = env::temp_dir().join(format!("mdict-pack-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("res/img")).unwrap();
+        for (name, size) in [("a.css", 10), ("img/b.png", 100), ("img/c.png", 100)] {
+            fs::write(dir.join("res").join(name), vec![b'x'; size]).unwrap();
+        }
+        let mdx = dir.join("dict.mdx");
+        let written = MddWriter::new("dict")
+            .volume_size(100)
+            .write_dir(dir.join("res"), mdx.with_extension("mdd"))
+            .unwrap();
+
+        // dict.mdd dict.1.mdd dict.2.mdd
+        let paths = mdd_paths(mdx.to_str().unwrap());
+        assert_eq!(paths, written);
+        assert_eq!(paths.len(), 3);
+        let found = paths
+            .iter()
+            .filter_map(|path| Mdd::open(path).unwrap().get("img/c.png").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![vec![b'x'; 100]]);
+
+        // 缺少的分卷之后的文件不再查找
+        fs::remove_file(&paths[1]).unwrap();
+        assert_eq!(mdd_paths(mdx.to_str().unwrap()), vec![paths[0].clone()]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,23 +1,116 @@
+use actix_web::error::ErrorInternalServerError;
 use actix_web::{web, HttpResponse, Result};
 use serde_derive::Deserialize;
 
 use crate::lucky;
 use crate::query::query;
+use crate::registry::Registry;
+use crate::resource::content_type;
 
 #[derive(Deserialize, Debug)]
 pub struct QueryForm {
     word: String,
 }
 
-pub(crate) async fn handle_query(params: web::Form<QueryForm>) -> Result<HttpResponse> {
-    Ok(HttpResponse::Ok()
-        .content_type("text/plain")
-        .body(format!("{}", query(params.word.clone()))))
+pub(crate) async fn handle_query(
+    params: web::Form<QueryForm>,
+    registry: web::Data<Registry>,
+) -> Result<HttpResponse> {
+    // query 会锁sqlite和解压record block, 放到线程池中执行, 不阻塞actix的worker
+    let word = params.into_inner().word;
+    let result = web::block(move || query(word, &registry)).await?;
+    Ok(HttpResponse::Ok().content_type("text/plain").body(result))
 }
 
-pub(crate) async fn handle_lucky() -> Result<HttpResponse> {
+pub(crate) async fn handle_lucky(registry: web::Data<Registry>) -> Result<HttpResponse> {
     let word = lucky::lucky_word();
-    Ok(HttpResponse::Ok()
-        .content_type("text/plain")
-        .body(format!("{}", query(word))))
+    let result = web::block(move || query(word, &registry)).await?;
+    Ok(HttpResponse::Ok().content_type("text/plain").body(result))
+}
+
+/// /res/{dict}/{path}: 从词典的mdd中读取资源文件
+pub(crate) async fn handle_resource(
+    params: web::Path<(String, String)>,
+    registry: web::Data<Registry>,
+) -> Result<HttpResponse> {
+    let (dict, path) = params.into_inner();
+    let key = path.clone();
+    let resource = web::block(move || match registry.get(&dict) {
+        Some(dict) => dict.resource(&key),
+        None => Ok(None),
+    })
+    .await?
+    .map_err(ErrorInternalServerError)?;
+    match resource {
+        Some(bytes) => Ok(HttpResponse::Ok()
+            .content_type(content_type(&path))
+            .body(bytes)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+    use mdict_rs::mdict::{Mdd, MddWriter, Mdx, MdxWriter};
+
+    use crate::app_config;
+    use crate::registry::{Dictionary, Registry};
+
+    fn registry() -> Registry {
+        let mut mdx = vec![];
+        MdxWriter::new("test")
+            .write([("apple", r#"<link href="O8C.css"><img src="uk_pron.png">apple"#)], &mut mdx)
+            .unwrap();
+        let mut mdd = vec![];
+        MddWriter::new("test")
+            .write([("O8C.css", b"body{}".to_vec()), ("uk_pron.png", b"PNG".to_vec())], &mut mdd)
+            .unwrap();
+        Registry::new(vec![Dictionary {
+            name: "test".to_string(),
+            mdx: Mdx::new(&mdx).unwrap(),
+            mdd: vec![Mdd::new(&mdd).unwrap()],
+            db: None,
+        }])
+    }
+
+    #[actix_web::test]
+    async fn resource_route() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(registry()))
+                .configure(app_config),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/res/test/O8C.css").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "text/css");
+        assert_eq!(test::read_body(res).await, "body{}");
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/res/test/uk_pron.png").to_request()).await;
+        assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "image/png");
+
+        for uri in ["/res/test/missing.png", "/res/nope/O8C.css"] {
+            let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn query_links_resources_to_dict() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(registry()))
+                .configure(app_config),
+        )
+        .await;
+        let req = test::TestRequest::post().uri("/query").set_form([("word", "apple")]).to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"<link href="/res/test/O8C.css">"#), "{}", body);
+        assert!(body.contains(r#"<img src="/res/test/uk_pron.png">"#), "{}", body);
+    }
 }
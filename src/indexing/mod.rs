@@ -2,55 +2,154 @@ use std::fs;
 use std::path::PathBuf;
 
 use anyhow::Context;
-use log::info;
+use log::{error, info};
+use rayon::prelude::*;
 use rusqlite::{Connection, params};
 
-use crate::mdict::mdx::Mdx;
+use crate::config::passcode;
+use mdict_rs::mdict::{fold_key, Mdx};
+
+/// MDX_INDEX表结构的版本, 保存在sqlite的user_version中, 表结构变化时旧的db会重新生成
+/// 1: 同一个词的多条释义都保留, 按文件中的顺序
+/// 2: 增加key列, 按header中的 KeyCaseSensitive 和 StripKey 处理后的text, 查询时使用
+/// 3: 增加folded列, 全角转半角并去掉变音符号后的key, 精确匹配不到时使用
+const INDEX_VERSION: i32 = 3;
 
 /// indexing all mdx files into db
+/// 每个词典有自己的db文件, 多个词典同时indexing
 pub(crate) fn indexing(files: &[&str], reindex: bool) {
-    for file in files {
-        let db_file = format!("{}{}", file.to_string(), ".db");
+    files.par_iter().for_each(|file| {
+        let db_file = format!("{}{}", file, ".db");
         if PathBuf::from(&db_file).exists() {
-            if reindex {
+            if reindex || index_version(&db_file) != INDEX_VERSION {
                 fs::remove_file(&db_file).expect("remove old db file error");
                 info!("old db file:{} removed", &db_file);
-                mdx_to_sqlite(&file).unwrap();
+                index_file(file);
             }
         } else {
-            mdx_to_sqlite(&file).unwrap();
+            index_file(file);
         }
+    });
+}
+
+fn index_version(db_file: &str) -> i32 {
+    Connection::open(db_file)
+        .and_then(|conn| conn.query_row("PRAGMA user_version", [], |row| row.get(0)))
+        .unwrap_or(0)
+}
+
+/// 一个词典解析失败不影响其他词典
+fn index_file(file: &str) {
+    if let Err(e) = mdx_to_sqlite(file) {
+        error!("indexing {} failed: {:?}", file, e);
     }
 }
 
 /// mdx entries and definition to sqlite table
+/// 失败时删除db文件, 不会留下不完整的db被当作已经indexing过
 pub(crate) fn mdx_to_sqlite(file: &str) -> anyhow::Result<()> {
-    let db_file = format!("{}{}", file.to_string(), ".db");
-    let mut conn = Connection::open(&db_file).unwrap();
-    let mdx = Mdx::new(&fs::read(file)?);
+    let db_file = format!("{}{}", file, ".db");
+    // 先解析mdx, 失败时不会创建db文件
+    let mdx = Mdx::open_with_passcode(file, passcode(file).as_ref())
+        .with_context(|| format!("parse mdx file {} failed", file))?;
+    let result = write_index(&mdx, &db_file);
+    if result.is_err() {
+        let _ = fs::remove_file(&db_file);
+    }
+    result
+}
+
+fn write_index(mdx: &Mdx, db_file: &str) -> anyhow::Result<()> {
+    let mut conn = Connection::open(db_file).with_context(|| format!("open db file {} failed", db_file))?;
 
-    conn.execute(
+    // 同一个词可能有多条释义(homographs), text不能作为主键, 按rowid保持文件中的顺序
+    conn.execute_batch(
         "create table if not exists MDX_INDEX (
-                text text primary key not null ,
-                def text not null
-         )",
-        params![],
+                text text not null ,
+                def text not null ,
+                key text not null ,
+                folded text not null
+         );
+         create index if not exists MDX_INDEX_KEY on MDX_INDEX (key);
+         create index if not exists MDX_INDEX_FOLDED on MDX_INDEX (folded);",
     )
         .with_context(|| "create table failed")?;
-    println!("table crated for {:?}", &db_file);
+    println!("table crated for {:?}", db_file);
 
     let tx = conn
         .transaction()
         .with_context(|| "get transaction from connection failed")?;
 
     for r in mdx.items() {
+        let r = r?;
+        let key = mdx.header().normalize_key(r.text());
+        let folded = mdx.header().normalize_key(&fold_key(r.text()));
         tx.execute(
-            "insert or replace into MDX_INDEX values (?,?)",
-            params![r.text, r.definition],
+            "insert into MDX_INDEX values (?,?,?,?)",
+            params![r.text(), r.definition(), key, folded],
         )
             .with_context(|| "insert MDX_INDEX table error")?;
     }
+    // 所有record都写入之后才标记版本, 和数据在同一个transaction中提交
+    tx.pragma_update(None, "user_version", INDEX_VERSION)
+        .with_context(|| "set user_version failed")?;
     tx.commit().with_context(|| "transaction commit error")?;
-    conn.close().expect("close db connection failed");
+    conn.close().map_err(|(_, e)| e).with_context(|| "close db connection failed")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use mdict_rs::mdict::{Compression, MdxWriter};
+
+    use super::*;
+
+    fn write_mdx(name: &str, corrupt: bool) -> String {
+        let entries: Vec<(String, String)> = (0..50).map(|i| (format!("w{:02}", i), format!("def {}", i))).collect();
+        let mut data = vec![];
+        MdxWriter::new(name)
+            .compression(Compression::Zlib)
+            .write(entries, &mut data)
+            .unwrap();
+        if corrupt {
+            // 最后一个record block的数据, key block仍然可以解析
+            let len = data.len();
+            for b in &mut data[len - 4..] {
+                *b ^= 0xff;
+            }
+        }
+        let file = std::env::temp_dir().join(format!("mdict-{}-{}.mdx", name, std::process::id()));
+        fs::write(&file, data).unwrap();
+        file.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn index_version_is_set_after_inserts() {
+        let file = write_mdx("indexing", false);
+        let db_file = format!("{}.db", file);
+        mdx_to_sqlite(&file).unwrap();
+        let version = index_version(&db_file);
+        let count: i64 = Connection::open(&db_file)
+            .unwrap()
+            .query_row("select count(*) from MDX_INDEX", [], |row| row.get(0))
+            .unwrap();
+        fs::remove_file(&file).unwrap();
+        fs::remove_file(&db_file).unwrap();
+        assert_eq!(version, INDEX_VERSION);
+        assert_eq!(count, 50);
+    }
+
+    #[test]
+    fn corrupt_record_block_leaves_no_db() {
+        let file = write_mdx("indexing-corrupt", true);
+        let db_file = format!("{}.db", file);
+        // mdx可以打开, 在写入db时才失败
+        assert!(Mdx::open(&file).is_ok());
+        let result = mdx_to_sqlite(&file);
+        let exists = PathBuf::from(&db_file).exists();
+        fs::remove_file(&file).unwrap();
+        assert!(result.is_err());
+        // 下次启动时重新indexing, 查询时直接读mdx
+        assert!(!exists);
+    }
+}
@@ -0,0 +1,14 @@
+//! mdx/mdd 词典文件解析
+//!
+//! ```no_run
+//! use mdict_rs::mdict::Mdx;
+//!
+//! let mdx = Mdx::open("./resources/mdx/en/牛津高阶8.mdx")?;
+//! println!("{}", mdx.header().title);
+//! for definition in mdx.lookup("apple")? {
+//!     println!("{}", definition);
+//! }
+//! # Ok::<(), mdict_rs::mdict::MdictError>(())
+//! ```
+pub mod mdict;
+mod util;
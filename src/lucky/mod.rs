@@ -5,7 +5,7 @@ use rand::{thread_rng, Rng};
 pub fn lucky_word() -> String {
     let word_list: Vec<&str> = STRING_LINES.lines().collect();
     let random_index = thread_rng().gen_range(0..word_list.len());
-    return word_list[random_index].to_string();
+    word_list[random_index].to_string()
 }
 
 const STRING_LINES: &str = r#"abjure
@@ -1,26 +1,27 @@
 use std::error::Error;
+use std::sync::Arc;
 
-use actix_files;
 use actix_web::{App, HttpServer, middleware, web};
-use pretty_env_logger;
 
 use crate::config::{MDX_FILES, static_path};
-use crate::handlers::{handle_lucky, handle_query};
+use crate::handlers::{handle_lucky, handle_query, handle_resource};
 use crate::indexing::indexing;
+use crate::registry::Registry;
 
 mod config;
 mod handlers;
 mod indexing;
 mod lucky;
-mod mdict;
 mod query;
-mod util;
+mod registry;
+mod resource;
 
 fn app_config(config: &mut web::ServiceConfig) {
     config.service(
         web::scope("")
             .service(web::resource("/query").route(web::post().to(handle_query)))
             .service(web::resource("/lucky").route(web::get().to(handle_lucky)))
+            .service(web::resource("/res/{dict}/{path:.*}").route(web::get().to(handle_resource)))
             // .wrap(middleware::DefaultHeaders::new().add(("Cache-Control", "max-age=86400")))
             .service(
                 actix_files::Files::new("/", static_path().unwrap().to_str().unwrap())
@@ -34,11 +35,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
 
     indexing(MDX_FILES, false);
+    // 所有worker共享同一份词典
+    let registry = web::Data::from(Arc::new(Registry::load(MDX_FILES)));
 
     println!("app serve on http://127.0.0.1:8181");
 
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         App::new()
+            .app_data(registry.clone())
             .wrap(middleware::Logger::default())
             .configure(app_config)
     })
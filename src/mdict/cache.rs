@@ -0,0 +1,205 @@
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+use serde_derive::Serialize;
+
+use crate::mdict::error::MdictError;
+
+/// 默认最多缓存16MB解压后的block
+pub const DEFAULT_CACHE_CAPACITY: usize = 16 * 1024 * 1024;
+
+/// 缓存的block, 用block在key block buf或record block buf中的offset区分
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum BlockId {
+    Key(usize),
+    Record(usize),
+}
+
+/// 解压后的key block和record block的LRU缓存, 按解压后的bytes大小限制内存
+/// 多个线程同时查词时共享, 解压在锁外进行, 同一个block可能被同时解压多次
+#[derive(Debug)]
+pub struct BlockCache {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    blocks: LruCache<BlockId, Arc<Vec<u8>>>,
+    // 缓存中所有block的大小之和
+    size: usize,
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+}
+
+/// 缓存的命中情况, 用于调整每个词典的缓存大小
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub blocks: usize,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+impl BlockCache {
+    /// capacity: 解压后的bytes上限, 0表示不缓存
+    pub fn new(capacity: usize) -> BlockCache {
+        BlockCache {
+            inner: Mutex::new(Inner {
+                blocks: LruCache::unbounded(),
+                size: 0,
+                capacity,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// 缓存中没有时使用 decompress 解压并放入缓存
+    pub fn get_or_insert_with<F>(&self, id: BlockId, decompress: F) -> Result<Arc<Vec<u8>>, MdictError>
+    where
+        F: FnOnce() -> Result<Vec<u8>, MdictError>,
+    {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(block) = inner.blocks.get(&id).cloned() {
+                inner.hits += 1;
+                return Ok(block);
+            }
+            inner.misses += 1;
+        }
+
+        let block = Arc::new(decompress()?);
+        let mut inner = self.inner.lock().unwrap();
+        if block.len() <= inner.capacity {
+            if let Some(old) = inner.blocks.put(id, block.clone()) {
+                inner.size -= old.len();
+            }
+            inner.size += block.len();
+            inner.evict();
+        }
+        Ok(block)
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        inner.evict();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        CacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            blocks: inner.blocks.len(),
+            size: inner.size,
+            capacity: inner.capacity,
+        }
+    }
+}
+
+impl Inner {
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            match self.blocks.pop_lru() {
+                Some((_, block)) => self.size -= block.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 返回是否调用了 decompress
+    fn get(cache: &BlockCache, offset: usize, len: usize) -> bool {
+        let mut decompressed = false;
+        let block = cache
+            .get_or_insert_with(BlockId::Record(offset), || {
+                decompressed = true;
+                Ok(vec![0; len])
+            })
+            .unwrap();
+        assert_eq!(block.len(), len);
+        decompressed
+    }
+
+    #[test]
+    fn evict_by_size() {
+        let cache = BlockCache::new(100);
+        assert!(get(&cache, 0, 40));
+        assert!(get(&cache, 1, 40));
+        // 0 变成最近使用的, 放入2时淘汰1
+        assert!(!get(&cache, 0, 40));
+        assert!(get(&cache, 2, 40));
+        let stats = cache.stats();
+        assert_eq!((stats.blocks, stats.size), (2, 80));
+        assert!(!get(&cache, 0, 40));
+        assert!(!get(&cache, 2, 40));
+        assert!(get(&cache, 1, 40));
+    }
+
+    #[test]
+    fn block_larger_than_capacity_is_not_cached() {
+        let cache = BlockCache::new(100);
+        assert!(get(&cache, 0, 40));
+        assert!(get(&cache, 1, 101));
+        assert!(get(&cache, 1, 101));
+        // 没有为了放不下的block淘汰其他block
+        assert!(!get(&cache, 0, 40));
+        let stats = cache.stats();
+        assert_eq!((stats.blocks, stats.size), (1, 40));
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let cache = BlockCache::new(0);
+        assert!(get(&cache, 0, 1));
+        assert!(get(&cache, 0, 1));
+        let stats = cache.stats();
+        assert_eq!((stats.blocks, stats.size, stats.misses), (0, 0, 2));
+    }
+
+    #[test]
+    fn shrink_capacity() {
+        let cache = BlockCache::new(100);
+        for offset in 0..4 {
+            get(&cache, offset, 25);
+        }
+        cache.set_capacity(50);
+        let stats = cache.stats();
+        assert_eq!((stats.blocks, stats.size, stats.capacity), (2, 50, 50));
+        // 保留最近使用的两个
+        assert!(!get(&cache, 2, 25));
+        assert!(!get(&cache, 3, 25));
+        cache.set_capacity(0);
+        assert_eq!(cache.stats().size, 0);
+    }
+
+    #[test]
+    fn count_hits_and_misses() {
+        let cache = BlockCache::new(100);
+        get(&cache, 0, 10);
+        get(&cache, 0, 10);
+        get(&cache, 0, 10);
+        get(&cache, 1, 10);
+        // key block 和 record block 的offset相同也是不同的block
+        let key = cache.get_or_insert_with(BlockId::Key(0), || Ok(vec![1])).unwrap();
+        assert_eq!(*key, vec![1]);
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.blocks, stats.size), (2, 3, 3, 21));
+    }
+
+    #[test]
+    fn decompress_error_is_not_cached() {
+        let cache = BlockCache::new(100);
+        let err = cache.get_or_insert_with(BlockId::Record(0), || Err(MdictError::Malformed("bad".into())));
+        assert!(err.is_err());
+        assert!(get(&cache, 0, 10));
+        assert_eq!(cache.stats().misses, 2);
+    }
+}
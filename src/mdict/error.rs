@@ -0,0 +1,89 @@
+use nom::error::{ErrorKind, ParseError};
+use thiserror::Error;
+
+/// 解析或写mdx/mdd文件时的错误
+#[derive(Debug, Clone, Error)]
+pub enum MdictError {
+    /// header 或 key block header 的 adler32 校验失败, 文件损坏
+    #[error("{part} checksum mismatch: expected {expected:#010x}, actual {actual:#010x}")]
+    ChecksumMismatch {
+        part: &'static str,
+        expected: u32,
+        actual: u32,
+    },
+    /// GeneratedByEngineVersion 不是 1.x 2.x 3.x
+    #[error("unsupported mdict engine version: {0}")]
+    UnsupportedVersion(String),
+    /// block 的压缩方式不是 0 1 2
+    #[error("unknown compression method: {0}")]
+    UnknownCompression(u32),
+    /// block 的加密方式不是 0 1 2
+    #[error("unknown encryption method: {0}")]
+    UnknownEncryption(u32),
+    /// Encrypted="1" 的词典没有提供注册码
+    #[error("user identification is needed to read encrypted file")]
+    PasscodeRequired,
+    /// 注册码格式不对, 注册码和user id不匹配时得到的是 Decompress 或 Malformed
+    #[error("invalid registration code")]
+    InvalidPasscode,
+    /// header 中的 Encoding 不认识, 或者文本无法解码
+    #[error("bad encoding: {0}")]
+    BadEncoding(String),
+    /// header 缺少必需的属性
+    #[error("missing header attribute: {0}")]
+    MissingAttribute(&'static str),
+    /// block 解压失败
+    #[error("decompress failed: {0}")]
+    Decompress(String),
+    /// 文件不完整
+    #[error("truncated data")]
+    Truncated,
+    /// 长度 offset 等信息不一致
+    #[error("malformed data: {0}")]
+    Malformed(String),
+    /// 读写文件失败
+    #[error("io error: {0}")]
+    Io(String),
+    /// 写mdx/mdd时key没有按 Header::normalize_key 排序
+    #[error("keys are not sorted: {key:?} after {prev:?}")]
+    UnsortedKeys { prev: String, key: String },
+    /// 写mdx/mdd时block压缩失败
+    #[error("compress failed: {0}")]
+    Compress(String),
+}
+
+/// 所有nom parser使用MdictError作为错误类型
+pub(crate) type ParseResult<'a, T> = nom::IResult<&'a [u8], T, MdictError>;
+
+/// 直接结束解析, 不会被many0之类的combinator当作可以恢复的错误
+pub(crate) fn failure<'a, T>(e: MdictError) -> ParseResult<'a, T> {
+    Err(nom::Err::Failure(e))
+}
+
+impl ParseError<&[u8]> for MdictError {
+    fn from_error_kind(_input: &[u8], kind: ErrorKind) -> Self {
+        match kind {
+            ErrorKind::Eof => MdictError::Truncated,
+            _ => MdictError::Malformed(format!("{:?}", kind)),
+        }
+    }
+
+    fn append(_input: &[u8], _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl From<std::io::Error> for MdictError {
+    fn from(e: std::io::Error) -> Self {
+        MdictError::Io(e.to_string())
+    }
+}
+
+impl From<nom::Err<MdictError>> for MdictError {
+    fn from(e: nom::Err<MdictError>) -> Self {
+        match e {
+            nom::Err::Incomplete(_) => MdictError::Truncated,
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+        }
+    }
+}
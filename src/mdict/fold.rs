@@ -0,0 +1,39 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// 查词时宽松匹配的形式, 精确匹配不到时使用
+/// NFKC: 全角转半角 e.g. "ＡＢＣ１" -> "ABC1", 半角片假名转全角 e.g. "ｶﾀｶﾅ" -> "カタカナ"
+/// 去掉拉丁字母等的变音符号 e.g. "café" -> "cafe", "naïve" -> "naive"
+/// 日文的浊音符号不是变音符号, "が" 不会变成 "か"
+pub fn fold_key(key: &str) -> String {
+    key.nfkc()
+        .collect::<String>()
+        .nfd()
+        .filter(|c| !is_diacritic(*c))
+        .nfc()
+        .collect()
+}
+
+/// Combining Diacritical Marks U+0300..U+036F
+fn is_diacritic(c: char) -> bool {
+    ('\u{0300}'..='\u{036f}').contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fold_width_and_diacritics() {
+        assert_eq!(fold_key("ＡＢＣ１"), "ABC1");
+        assert_eq!(fold_key("ｶﾀｶﾅ"), "カタカナ");
+        assert_eq!(fold_key("café"), "cafe");
+        assert_eq!(fold_key("cafe\u{301}"), "cafe");
+        assert_eq!(fold_key("naïve"), "naive");
+    }
+
+    #[test]
+    fn fold_keeps_japanese_voiced_marks() {
+        assert_eq!(fold_key("が"), "が");
+        assert_eq!(fold_key("ｶﾞ"), "ガ");
+    }
+}
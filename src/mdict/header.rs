@@ -6,75 +6,250 @@ use log::info;
 use nom::multi::length_data;
 use nom::number::complete::{be_u32, le_u32};
 use nom::sequence::tuple;
-use nom::{IResult, Slice};
 use regex::Regex;
+use serde_derive::Serialize;
+use xxhash_rust::xxh64::xxh64;
 
-#[derive(Debug)]
+use crate::mdict::error::{failure, MdictError, ParseResult};
+
+#[derive(Debug, Clone, Serialize)]
 pub enum Version {
+    /// GeneratedByEngineVersion 1.x, 数字是4 bytes
     V1,
+    /// GeneratedByEngineVersion 2.x, 数字是8 bytes
     V2,
+    /// MDict 3.0: header是utf-8, key和record分成多个section, 使用UUID的xxhash作为key
+    V3,
 }
 
 /// mdx头部信息
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Header {
+    /// 由 GeneratedByEngineVersion 得到的文件格式版本
     pub version: Version,
+    /// GeneratedByEngineVersion, e.g. "2.0"
+    pub engine_version: String,
     /**
      * encryption flag
      * "0" - no encryption
-     * "1" - encrypt record block
+     * "1" - encrypt key block header, 需要注册码
      * "2" - encrypt key info block
      * e.g. 牛津 "0" 朗文 "2"
      */
     pub encrypted: String,
-    // record bytes encoding, e.g. "UTF-8"
+    /// record bytes encoding, e.g. "UTF-8"
     pub encoding: String,
+    /// 注册方式 "EMail" 或 "DeviceID", 用于 Encrypted="1" 时解密注册码
+    pub register_by: String,
+    /// 词典名, Title
+    pub title: String,
+    /// 词典说明, Description, 通常是html
+    pub description: String,
+    /// 压缩释义用的样式表, 见 StyleSheet
+    pub stylesheet: String,
+    /// 释义格式 "Html" 或 "Text"
+    pub format: String,
+    /// CreationDate, e.g. "2024-1-1"
+    pub creation_date: String,
+    /// 查询时key是否区分大小写, "No" 时按小写比较
+    pub key_case_sensitive: bool,
+    /// 查询时是否去掉key中的标点和空格
+    pub strip_key: bool,
+    /// Compact (有的词典写作 Compat), 释义是否用StyleSheet压缩过
+    pub compact: bool,
+    /// Left2Right, 文字方向是否从左到右
+    pub left2right: bool,
+    /// header中的所有属性
+    pub attrs: HashMap<String, String>,
+    // Encrypted="1" 时由注册码和user id得到的解密key
+    #[serde(skip)]
+    pub(crate) record_key: Option<Vec<u8>>,
 }
 
-pub fn parse_header(data: &[u8]) -> IResult<&[u8], Header> {
+impl Header {
+    /// Encrypted 是一个bit flag
+    pub(crate) fn encrypted_flag(&self) -> u8 {
+        self.encrypted.trim().parse::<u8>().unwrap_or(0)
+    }
+
+    /// mdict比较key的形式, key block中的key也是按这个形式排序的
+    /// KeyCaseSensitive="No" 时转成小写, StripKey="Yes" 时去掉标点和空格
+    /// e.g. "A-Priori" -> "apriori"
+    pub fn normalize_key(&self, key: &str) -> String {
+        let key: String = if self.strip_key {
+            key.chars()
+                .filter(|c| !c.is_whitespace() && !c.is_ascii_punctuation())
+                .collect()
+        } else {
+            key.to_string()
+        };
+        if self.key_case_sensitive {
+            key
+        } else {
+            key.to_lowercase()
+        }
+    }
+}
+
+pub(crate) fn parse_header(data: &[u8]) -> ParseResult<'_, Header> {
     // length_data(be_u32) 先读取一个be_u32 number,然后根据number读取对应长度bytes
     let (data, (header_buf, checksum)) = tuple((length_data(be_u32), le_u32))(data)?;
     // &[8] 实现Read接口
-    assert_eq!(adler32(header_buf).unwrap(), checksum);
-    // string from utf_16le encoding
-    let info = UTF_16LE
-        .decode(header_buf, encoding::DecoderTrap::Strict)
-        .unwrap();
+    let actual = adler32(header_buf).unwrap();
+    if actual != checksum {
+        return failure(MdictError::ChecksumMismatch {
+            part: "header",
+            expected: checksum,
+            actual,
+        });
+    }
+    // v1 v2 是utf-16le编码, 以\x00\x00结尾; v3 是utf-8编码, 以\x00结尾
+    let info = if header_buf.ends_with(b"\x00\x00") {
+        UTF_16LE
+            .decode(header_buf, encoding::DecoderTrap::Strict)
+            .map_err(|e| nom::Err::Failure(MdictError::BadEncoding(e.to_string())))?
+    } else {
+        String::from_utf8_lossy(header_buf).to_string()
+    };
 
     let re = Regex::new(r#"(\w+)="((.|\r\n|[\r\n])*?)""#).unwrap();
     let mut attrs = HashMap::new();
     for cap in re.captures_iter(info.as_str()) {
-        attrs.insert(cap[1].to_string(), cap[2].to_string());
+        attrs.insert(cap[1].to_string(), unescape_entities(&cap[2]));
     }
 
     info!(">>>the header content: {:?}", &attrs);
 
-    let version = attrs
-        .get("GeneratedByEngineVersion")
-        .unwrap()
-        .trim()
-        .slice(0..1)
-        .parse::<u8>()
-        .unwrap();
-
-    let version = match version {
-        1 => Version::V1,
-        2 => Version::V2,
-        _ => panic!("unsupported mdx engine version!, {}", &version),
+    let engine_version = match attrs.get("GeneratedByEngineVersion") {
+        Some(v) => v.trim(),
+        None => return failure(MdictError::MissingAttribute("GeneratedByEngineVersion")),
+    };
+
+    let version = match engine_version.chars().next() {
+        Some('1') => Version::V1,
+        Some('2') => Version::V2,
+        Some('3') => Version::V3,
+        _ => {
+            return failure(MdictError::UnsupportedVersion(
+                engine_version.to_string(),
+            ))
+        }
     };
 
     // "0" "2" "3"
-    let encrypted = attrs.get("Encrypted").unwrap().to_string();
+    let encrypted = match attrs.get("Encrypted") {
+        Some(v) => v.to_string(),
+        None => return failure(MdictError::MissingAttribute("Encrypted")),
+    };
+
+    // "UTF-8", mdd文件没有这个属性或者为空
+    let encoding = attrs.get("Encoding").cloned().unwrap_or_default();
+
+    let register_by = attrs.get("RegisterBy").cloned().unwrap_or_default();
 
-    // "UTF-8"
-    let encoding = attrs.get("Encoding").unwrap().to_string();
+    // v3 的block使用UUID前后两半的xxh64拼接成的16 bytes作为解密key
+    let record_key = match (&version, attrs.get("UUID")) {
+        (Version::V3, Some(uuid)) => {
+            let uuid = uuid.as_bytes();
+            let mid = uuid.len().div_ceil(2);
+            let mut key = xxh64(&uuid[..mid], 0).to_be_bytes().to_vec();
+            key.extend_from_slice(&xxh64(&uuid[mid..], 0).to_be_bytes());
+            Some(key)
+        }
+        _ => None,
+    };
 
     Ok((
         data,
         Header {
             version,
+            engine_version: engine_version.to_string(),
             encrypted,
             encoding,
+            register_by,
+            title: attr(&attrs, &["Title"]).unwrap_or_default(),
+            description: attr(&attrs, &["Description"]).unwrap_or_default(),
+            stylesheet: attr(&attrs, &["StyleSheet"]).unwrap_or_default(),
+            format: attr(&attrs, &["Format"]).unwrap_or_default(),
+            creation_date: attr(&attrs, &["CreationDate"]).unwrap_or_default(),
+            key_case_sensitive: yes(&attrs, &["KeyCaseSensitive"], false),
+            strip_key: yes(&attrs, &["StripKey", "Stripkey"], true),
+            compact: yes(&attrs, &["Compact", "Compat"], false),
+            left2right: yes(&attrs, &["Left2Right"], true),
+            attrs,
+            record_key,
         },
     ))
 }
+
+/// 同一个属性在不同的词典里写法可能不同, e.g. StripKey Stripkey
+fn attr(attrs: &HashMap<String, String>, names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| attrs.get(*name)).cloned()
+}
+
+/// "Yes" / "No" 属性
+fn yes(attrs: &HashMap<String, String>, names: &[&str], default: bool) -> bool {
+    match attr(attrs, names) {
+        Some(v) => v.trim().eq_ignore_ascii_case("yes"),
+        None => default,
+    }
+}
+
+/// 写header时转义属性值, unescape_entities 的逆操作
+pub(crate) fn escape_entities(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// header中的属性值是转义过的, e.g. Description="&lt;b&gt;..."
+fn unescape_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// v2 header: 长度, utf-16le 的header文本, adler32
+    fn parse(attrs: &str) -> Header {
+        let text = format!(r#"<Dictionary GeneratedByEngineVersion="2.0" Encrypted="0" {}/>{}"#, attrs, "\r\n\0");
+        let buf: Vec<u8> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let mut data = (buf.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&buf);
+        data.extend_from_slice(&adler32(buf.as_slice()).unwrap().to_le_bytes());
+        parse_header(&data).unwrap().1
+    }
+
+    #[test]
+    fn normalize_key_by_default() {
+        let header = parse("");
+        assert!(!header.key_case_sensitive);
+        assert!(header.strip_key);
+        assert_eq!(header.normalize_key("Apple"), "apple");
+        assert_eq!(header.normalize_key("apple"), "apple");
+        assert_eq!(header.normalize_key("a-priori"), "apriori");
+        assert_eq!(header.normalize_key("A Priori"), "apriori");
+    }
+
+    #[test]
+    fn normalize_key_case_sensitive() {
+        let header = parse(r#"KeyCaseSensitive="Yes""#);
+        assert_eq!(header.normalize_key("Apple"), "Apple");
+        assert_eq!(header.normalize_key("a-priori"), "apriori");
+    }
+
+    #[test]
+    fn normalize_key_without_strip() {
+        let header = parse(r#"StripKey="No""#);
+        assert_eq!(header.normalize_key("Apple"), "apple");
+        assert_eq!(header.normalize_key("a-priori"), "a-priori");
+
+        let header = parse(r#"KeyCaseSensitive="Yes" StripKey="No""#);
+        assert_eq!(header.normalize_key("A-Priori"), "A-Priori");
+    }
+}
@@ -1,24 +1,36 @@
-use std::{io::Read, str};
+use std::{borrow::Cow, str};
 
 use adler32::adler32;
-use encoding::label::encoding_from_whatwg_label;
-use flate2::read::ZlibDecoder;
+use encoding::EncodingRef;
 use nom::{
-    bytes::complete::{take, take_till},
+    bytes::complete::take,
     combinator::map,
     multi::{length_data, many0},
     number::complete::{be_u32, be_u64, le_u32},
     sequence::tuple,
-    IResult, Slice,
+    Slice,
 };
+use rayon::prelude::*;
 use ripemd::{Digest, Ripemd128};
 
+use crate::mdict::error::{failure, MdictError, ParseResult};
 use crate::mdict::header::{Header, Version};
+use crate::util::block_data_size;
+use crate::util::block_key;
+use crate::util::decompress_block;
+use crate::util::decrypt_block;
 use crate::util::fast_decrypt;
+use crate::util::is_utf16;
+use crate::util::salsa_decrypt;
+use crate::util::text_decoder;
 use crate::util::text_len_parser_v1;
+use crate::util::text_len_parser_v1_utf16;
 use crate::util::text_len_parser_v2;
+use crate::util::text_len_parser_v2_utf16;
+use crate::util::text_parser;
+use crate::util::utf16_text_parser;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct KeyBlockHeader {
     pub block_num: usize,
     pub entry_num: usize,
@@ -34,6 +46,13 @@ pub struct KeyBlockHeader {
 pub struct KeyBlockSize {
     pub csize: usize,
     pub dsize: usize,
+    // block中entry的个数, v3没有key block info, 是解压后得到的
+    pub entries_num: usize,
+    // block中的第一个和最后一个key, 查词时用于二分查找key所在的block
+    pub first_key: String,
+    pub last_key: String,
+    // block在key block buf中的offset, v3的每个block前面还有dsize和csize
+    pub offset: usize,
 }
 
 /// 词典索引信息, 和实体词典的索引一样，一个text以及一个页码，不过这个页码是整个buf解压后的偏移量
@@ -46,17 +65,22 @@ pub struct Entry {
 
 pub fn parse_key_block_header<'a>(
     data: &'a [u8],
-    header: &'a Header,
-) -> IResult<&'a [u8], KeyBlockHeader> {
+    header: &Header,
+) -> ParseResult<'a, KeyBlockHeader> {
     return match header.version {
-        Version::V1 => parse_key_block_header_v1(data),
-        Version::V2 => parse_key_block_header_v2(data),
+        Version::V1 => parse_key_block_header_v1(data, header),
+        Version::V2 => parse_key_block_header_v2(data, header),
+        Version::V3 => unreachable!("v3 has no key block header, use parse_key_block_sizes_v3"),
     };
 
-    fn parse_key_block_header_v1(data: &[u8]) -> IResult<&[u8], KeyBlockHeader> {
+    fn parse_key_block_header_v1<'a>(
+        data: &'a [u8],
+        header: &Header,
+    ) -> ParseResult<'a, KeyBlockHeader> {
         let (data, info_buf) = take(16_usize)(data)?;
+        let info_buf = decrypt_key_block_header(info_buf, header).map_err(nom::Err::Failure)?;
         // map 接收一个parser和一个匿名fn, 将parser的结果传递给fn后得到返回值
-        let (_, kbh) = map(
+        let kbh: Result<_, nom::Err<MdictError>> = map(
             tuple((be_u32, be_u32, be_u32, be_u32)),
             |(block_num, entry_num, info_len, blocks_len)| KeyBlockHeader {
                 block_num: block_num as usize,
@@ -65,19 +89,30 @@ pub fn parse_key_block_header<'a>(
                 key_block_info_len: info_len as usize,
                 key_blocks_len: blocks_len as usize,
             },
-        )(info_buf)?;
-        Ok((data, kbh))
+        )(&info_buf[..]);
+        Ok((data, kbh.map_err(|e| nom::Err::Failure(e.into()))?.1))
     }
 
-    fn parse_key_block_header_v2(data: &[u8]) -> IResult<&[u8], KeyBlockHeader> {
+    fn parse_key_block_header_v2<'a>(
+        data: &'a [u8],
+        header: &Header,
+    ) -> ParseResult<'a, KeyBlockHeader> {
         // 5个元信息 和 v1相比多了一个key_block_info_decompressed_size 和一个 adler32 checksum
         let (data, info_buf) = take(40_usize)(data)?;
+        let info_buf = decrypt_key_block_header(info_buf, header).map_err(nom::Err::Failure)?;
         let (data, checksum) = be_u32(data)?;
 
-        // checksum info_buf
-        assert_eq!(adler32(info_buf).unwrap(), checksum);
+        // checksum 是对解密后的 info_buf 计算的
+        let actual = adler32(&info_buf[..]).unwrap();
+        if actual != checksum {
+            return failure(MdictError::ChecksumMismatch {
+                part: "key block header",
+                expected: checksum,
+                actual,
+            });
+        }
 
-        let (_, kbh) = map(
+        let kbh: Result<_, nom::Err<MdictError>> = map(
             tuple((be_u64, be_u64, be_u64, be_u64, be_u64)),
             |(
                 block_num,
@@ -92,140 +127,271 @@ pub fn parse_key_block_header<'a>(
                 key_block_info_len: key_block_info_len as usize,
                 key_blocks_len: key_blocks_len as usize,
             },
-        )(info_buf)?;
-        Ok((data, kbh))
+        )(&info_buf[..]);
+        Ok((data, kbh.map_err(|e| nom::Err::Failure(e.into()))?.1))
+    }
+
+    /// Encrypted="1": key block header 使用注册码得到的key做 Salsa20/8 加密
+    fn decrypt_key_block_header<'a>(
+        info_buf: &'a [u8],
+        header: &Header,
+    ) -> Result<Cow<'a, [u8]>, MdictError> {
+        if header.encrypted_flag() & 1 == 0 {
+            return Ok(Cow::Borrowed(info_buf));
+        }
+        let key = header
+            .record_key
+            .as_ref()
+            .ok_or(MdictError::PasscodeRequired)?;
+        salsa_decrypt(info_buf, key).map(Cow::Owned)
     }
 }
 
 /// Vec<(usize,usize)>: every key block compressed and decompressed size
 pub fn parse_key_block_info<'a>(
     data: &'a [u8],
-    block_info_len: usize,
-    header: &'a Header,
-) -> IResult<&'a [u8], Vec<KeyBlockSize>> {
-    return match &header.version {
-        Version::V1 => v1(data, block_info_len),
-        Version::V2 => v2(data, block_info_len, &header.encrypted),
+    kbh: &KeyBlockHeader,
+    header: &Header,
+) -> ParseResult<'a, Vec<KeyBlockSize>> {
+    let block_info_len = kbh.key_block_info_len;
+    let (data, mut blocks) = match &header.version {
+        Version::V1 => v1(data, block_info_len, header)?,
+        Version::V2 => v2(data, block_info_len, kbh.key_block_info_decompressed_len, header)?,
+        Version::V3 => unreachable!("v3 has no key block info, use parse_key_block_sizes_v3"),
     };
+    // v1 v2 的block是连续存放的, 所有block的大小之和不能超过 key blocks 的长度
+    let mut offset = 0;
+    for block in blocks.iter_mut() {
+        block.offset = offset;
+        offset = match offset.checked_add(block.csize) {
+            Some(offset) if offset <= kbh.key_blocks_len => offset,
+            _ => return failure(MdictError::Malformed("key block size out of key blocks".to_string())),
+        };
+    }
+    return Ok((data, blocks));
 
-    fn v1<'a>(data: &'a [u8], block_info_len: usize) -> IResult<&'a [u8], Vec<KeyBlockSize>> {
+    fn v1<'a>(
+        data: &'a [u8],
+        block_info_len: usize,
+        header: &Header,
+    ) -> ParseResult<'a, Vec<KeyBlockSize>> {
         let (data, block_info) = take(block_info_len)(data)?;
-        let key_blocks_size = decode_key_blocks_size_v1(block_info);
+        let key_blocks_size = decode_key_blocks_size_v1(block_info, &header.encoding)
+            .map_err(nom::Err::Failure)?;
         Ok((data, key_blocks_size))
     }
 
     fn v2<'a>(
         data: &'a [u8],
         block_info_len: usize,
-        encrypted: &str,
-    ) -> IResult<&'a [u8], Vec<KeyBlockSize>> {
+        block_info_decompressed_len: usize,
+        header: &Header,
+    ) -> ParseResult<'a, Vec<KeyBlockSize>> {
         let (left, block_info) = take(block_info_len)(data)?;
-        assert_eq!(block_info.slice(0..4), b"\x02\x00\x00\x00");
-
-        let mut key_block_info = vec![];
-
-        if encrypted == "0" {
-            ZlibDecoder::new(&block_info[8..])
-                .read_to_end(&mut key_block_info)
-                .unwrap();
+        if block_info.len() < 8 || block_info.slice(0..4) != b"\x02\x00\x00\x00" {
+            return failure(MdictError::Malformed(
+                "key block info is not zlib compressed".to_string(),
+            ));
         }
 
-        //decrypt
-        if encrypted == "2" || encrypted == "3" {
+        // Encrypted 的第二个bit表示 key block info 是否加密, "1" 只加密 key block header
+        let compressed = if header.encrypted_flag() & 2 == 0 {
+            Vec::from(&block_info[8..])
+        } else {
+            //decrypt
             let mut md = Ripemd128::new();
             let mut v = Vec::from(block_info.slice(4..8));
             let value: u32 = 0x3695;
             v.extend_from_slice(&value.to_le_bytes());
             md.update(v);
             let key = md.finalize();
-            let mut d = Vec::from(&block_info[0..8]);
-            let decrypted = fast_decrypt(&block_info[8..], key.as_slice());
-            d.extend(decrypted);
-            ZlibDecoder::new(&d[8..])
-                .read_to_end(&mut key_block_info)
-                .unwrap();
-        }
+            fast_decrypt(&block_info[8..], key.as_slice())
+        };
+        // 解压后的大小来自 key block header, 最多解压出这么多bytes
+        let key_block_info = decompress_block(compressed, 2, block_info_decompressed_len)
+            .map_err(nom::Err::Failure)?;
 
-        let entry_infos = decode_key_blocks_size_v2(&key_block_info[..]);
+        let entry_infos = decode_key_blocks_size_v2(&key_block_info[..], &header.encoding)
+            .map_err(nom::Err::Failure)?;
         Ok((left, entry_infos))
     }
 
     /// number of entries, num of bytes, first, num of bytes, last?
-    fn decode_key_blocks_size_v1(block_info: &[u8]) -> Vec<KeyBlockSize> {
+    fn decode_key_blocks_size_v1(
+        block_info: &[u8],
+        encoding: &str,
+    ) -> Result<Vec<KeyBlockSize>, MdictError> {
+        // v1 的 first/last key 没有结尾的 \x00, utf-16 的长度单位是 code unit
+        let text_len_parser: fn(&[u8]) -> ParseResult<'_, u32> = if is_utf16(encoding) {
+            text_len_parser_v1_utf16
+        } else {
+            text_len_parser_v1
+        };
+        let decoder = text_decoder(encoding)?;
         let mut parser = many0(map(
             tuple((
                 be_u32,
-                length_data(text_len_parser_v1),
-                length_data(text_len_parser_v1),
+                length_data(text_len_parser),
+                length_data(text_len_parser),
                 be_u32,
                 be_u32,
             )),
-            |(_, _, _, csize, dsize)| KeyBlockSize {
+            |(entries_num, first, last, csize, dsize)| KeyBlockSize {
                 csize: csize as usize,
                 dsize: dsize as usize,
+                entries_num: entries_num as usize,
+                first_key: decode_key(first, decoder),
+                last_key: decode_key(last, decoder),
+                offset: 0,
             },
         ));
-        let (remain, res) = parser(block_info).unwrap();
-        assert_eq!(
-            remain.len(),
-            0,
-            "failed: key block info parser left some data"
-        );
-        res
+        let (remain, res) = parser(block_info)?;
+        if !remain.is_empty() {
+            return Err(MdictError::Malformed(
+                "key block info parser left some data".to_string(),
+            ));
+        }
+        Ok(res)
     }
 
-    fn decode_key_blocks_size_v2(block_info: &[u8]) -> Vec<KeyBlockSize> {
+    fn decode_key_blocks_size_v2(
+        block_info: &[u8],
+        encoding: &str,
+    ) -> Result<Vec<KeyBlockSize>, MdictError> {
+        // utf-16 的 first/last key 长度单位是 code unit，结尾是两个字节的 \x00\x00
+        let text_len_parser: fn(&[u8]) -> ParseResult<'_, u32> = if is_utf16(encoding) {
+            text_len_parser_v2_utf16
+        } else {
+            text_len_parser_v2
+        };
+        let decoder = text_decoder(encoding)?;
         let mut parser = many0(map(
             tuple((
                 be_u64,
-                length_data(text_len_parser_v2),
-                length_data(text_len_parser_v2),
+                length_data(text_len_parser),
+                length_data(text_len_parser),
                 be_u64,
                 be_u64,
             )),
-            |(_, _, _, csize, dsize)| KeyBlockSize {
+            |(entries_num, first, last, csize, dsize)| KeyBlockSize {
                 csize: csize as usize,
                 dsize: dsize as usize,
+                entries_num: entries_num as usize,
+                first_key: decode_key(first, decoder),
+                last_key: decode_key(last, decoder),
+                offset: 0,
             },
         ));
-        let (remain, res) = parser(block_info).unwrap();
-        assert_eq!(remain.len(), 0);
-        res
+        let (remain, res) = parser(block_info)?;
+        if !remain.is_empty() {
+            return Err(MdictError::Malformed(
+                "key block info parser left some data".to_string(),
+            ));
+        }
+        Ok(res)
     }
 }
 
-/// 解析 key blocks
-pub fn parse_key_blocks<'a>(
-    data: &'a [u8],
-    key_blocks_len: usize,
+/// 解析 key blocks, buf 是所有key block的bytes
+/// 每个block在多个线程中解压, entries 按block的顺序
+/// v3 没有 key block info, 解压之后填上每个block的entry个数和first/last key
+pub fn parse_key_blocks(
+    buf: &[u8],
     header: &Header,
-    key_blocks_size: &'a Vec<KeyBlockSize>,
-) -> IResult<&'a [u8], Vec<Entry>> {
-    let (data, buf) = take(key_blocks_len)(data)?;
-    let mut buf = buf;
-
-    let mut key_entries: Vec<Entry> = vec![];
-
-    for info in key_blocks_size.iter() {
-        let (remain, decompressed) = key_block_parser(info.csize, info.dsize)(buf)?;
-        let (_, mut one_block_entries) = match &header.version {
-            Version::V1 => parse_block_items_v1(&decompressed[..], &header.encoding).unwrap(),
-            Version::V2 => parse_block_items_v2(&decompressed[..], &header.encoding).unwrap(),
-        };
+    key_blocks_size: &mut [KeyBlockSize],
+) -> Result<Vec<Entry>, MdictError> {
+    let blocks = key_blocks_size
+        .par_iter()
+        .map(|block| read_key_block(buf, block, header))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Version::V3 = header.version {
+        for (block, entries) in key_blocks_size.iter_mut().zip(&blocks) {
+            block.entries_num = entries.len();
+            if let (Some(first), Some(last)) = (entries.first(), entries.last()) {
+                block.first_key = first.text.clone();
+                block.last_key = last.text.clone();
+            }
+        }
+    }
+    Ok(blocks.into_iter().flatten().collect())
+}
+
+/// 解压一个key block得到其中的entries
+pub(crate) fn read_key_block(
+    buf: &[u8],
+    block: &KeyBlockSize,
+    header: &Header,
+) -> Result<Vec<Entry>, MdictError> {
+    let decompressed = decompress_key_block(buf, block, header)?;
+    parse_key_block_entries(&decompressed, header)
+}
+
+/// 解压一个key block
+pub(crate) fn decompress_key_block(
+    buf: &[u8],
+    block: &KeyBlockSize,
+    header: &Header,
+) -> Result<Vec<u8>, MdictError> {
+    let block_buf = buf.get(block.offset..).ok_or(MdictError::Truncated)?;
+    let (_, decompressed) =
+        key_block_parser(block.csize, block.dsize, header.record_key.clone())(block_buf)?;
+    Ok(decompressed)
+}
 
+/// 从解压后的key block得到其中的entries
+pub(crate) fn parse_key_block_entries(
+    decompressed: &[u8],
+    header: &Header,
+) -> Result<Vec<Entry>, MdictError> {
+    let (_, entries) = parse_block_items(decompressed, header)?;
+    Ok(entries)
+}
+
+/// v3 key data section: block number, total size, 然后是每个block的 dsize csize block
+/// block 解压后的格式和v2相同
+/// v3 没有 key block info, 每个block的first/last key要解压之后才能得到, 见 parse_key_blocks
+pub fn parse_key_block_sizes_v3(data: &[u8]) -> Result<Vec<KeyBlockSize>, MdictError> {
+    let (mut buf, (blocks_num, _total_size)) = tuple((be_u32, be_u64))(data)?;
+
+    let mut blocks = vec![];
+    for _ in 0..blocks_num {
+        let (remain, (dsize, csize)) = tuple((be_u32, be_u32))(buf)?;
+        blocks.push(KeyBlockSize {
+            csize: csize as usize,
+            dsize: dsize as usize,
+            entries_num: 0,
+            first_key: String::new(),
+            last_key: String::new(),
+            offset: data.len() - remain.len(),
+        });
+        let (remain, _) = take(csize)(remain)?;
         buf = remain;
-        key_entries.append(&mut one_block_entries);
     }
+    Ok(blocks)
+}
 
-    Ok((data, key_entries))
+/// key block info 中的 first/last key, v2 的结尾有 \x00
+fn decode_key(buf: &[u8], decoder: EncodingRef) -> String {
+    let text = decoder.decode(buf, encoding::DecoderTrap::Ignore).unwrap();
+    text.trim_end_matches('\0').to_string()
 }
 
-// TODO 可以合并
-fn parse_block_items_v1<'a>(data: &'a [u8], encoding: &'a str) -> IResult<&'a [u8], Vec<Entry>> {
+/// 解压后的key block: 每个key是 offset(v1 u32, v2 v3 u64) 加上以 \x00 结尾的text
+/// utf-16le的text以两个字节的 \x00\x00 结尾, 例如mdd中的资源路径 `\\uk_pron.png`
+fn parse_block_items<'a>(data: &'a [u8], header: &Header) -> ParseResult<'a, Vec<Entry>> {
+    let offset_parser: fn(&[u8]) -> ParseResult<'_, u64> = match &header.version {
+        Version::V1 => be_u32_as_u64,
+        Version::V2 | Version::V3 => |input| be_u64(input),
+    };
+    let text_parser: fn(&[u8]) -> ParseResult<'_, &[u8]> = if is_utf16(&header.encoding) {
+        utf16_text_parser
+    } else {
+        text_parser
+    };
+    let decoder = text_decoder(&header.encoding).map_err(nom::Err::Failure)?;
     let (remain, entries) = many0(map(
-        tuple((be_u32, take_till(|x| x == 0), take(1_usize))),
-        |(offset, buf, _)| {
-            let decoder = encoding_from_whatwg_label(encoding).unwrap();
+        tuple((offset_parser, text_parser)),
+        |(offset, buf)| {
             let text = decoder.decode(buf, encoding::DecoderTrap::Ignore).unwrap();
             Entry {
                 record_start_in_de_buf: offset as usize,
@@ -234,72 +400,99 @@ fn parse_block_items_v1<'a>(data: &'a [u8], encoding: &'a str) -> IResult<&'a [u
         },
     ))(data)?;
 
-    assert_eq!(remain.len(), 0);
+    if !remain.is_empty() {
+        return failure(MdictError::Malformed(
+            "key block parser left some data".to_string(),
+        ));
+    }
 
     Ok((remain, entries))
 }
 
-fn parse_block_items_v2<'a>(data: &'a [u8], encoding: &'a str) -> IResult<&'a [u8], Vec<Entry>> {
-    let (remain, sep) = many0(map(
-        tuple((be_u64, take_till(|x| x == 0), take(1_usize))),
-        |(offset, buf, _)| {
-            let decoder = encoding_from_whatwg_label(encoding).unwrap();
-            let text = decoder.decode(buf, encoding::DecoderTrap::Ignore).unwrap();
-            Entry {
-                record_start_in_de_buf: offset as usize,
-                text,
-            }
-        },
-    ))(data)?;
-
-    assert_eq!(remain.len(), 0);
-
-    Ok((remain, sep))
+fn be_u32_as_u64(input: &[u8]) -> ParseResult<'_, u64> {
+    map(be_u32, u64::from)(input)
 }
 
 /// 解析一个 key block 得到的是bytes
+/// record_key: 有注册码时使用注册码得到的key解密, 否则使用checksum的ripemd128
 fn key_block_parser<'a>(
     csize: usize,
     dsize: usize,
-) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Vec<u8>> {
-    map(
-        tuple((le_u32, take(4_usize), take(csize - 8))),
-        move |(enc, checksum, encrypted)| {
-            let enc_method = (enc >> 4) & 0xf;
-            let _enc_size = (enc >> 8) & 0xff;
-            let comp_method = enc & 0xf;
+    record_key: Option<Vec<u8>>,
+) -> impl FnMut(&'a [u8]) -> ParseResult<'a, Vec<u8>> {
+    move |data: &'a [u8]| {
+        let size = block_data_size(csize).map_err(nom::Err::Failure)?;
+        let (data, (enc, checksum, encrypted)) =
+            tuple((le_u32, take(4_usize), take(size)))(data)?;
+        let enc_method = (enc >> 4) & 0xf;
+        let enc_size = ((enc >> 8) & 0xff) as usize;
+        let comp_method = enc & 0xf;
+
+        let key = block_key(checksum, record_key.as_deref());
+        let decompressed = decrypt_block(encrypted, enc_method, enc_size, &key)
+            .and_then(|data| decompress_block(data, comp_method, dsize))
+            .map_err(nom::Err::Failure)?;
+
+        Ok((data, decompressed))
+    }
+}
 
-            let mut md = Ripemd128::new();
-            md.update(checksum);
-            let key = md.finalize();
+#[cfg(test)]
+mod tests {
+    use crate::mdict::header::parse_header;
+    use crate::mdict::MdxWriter;
+    use crate::util::encrypt_block;
+
+    use super::*;
+
+    #[test]
+    fn encrypted_key_blocks() {
+        let mut data = vec![];
+        for (i, key) in ["apple", "banana", "cherry"].iter().enumerate() {
+            data.extend_from_slice(&(i as u64 * 10).to_be_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.push(0);
+        }
+        for (comp_method, enc_method, enc_size) in [(2, 2, 8), (0, 2, 255), (1, 1, 12)] {
+            let block = encrypt_block(&data, comp_method, enc_method, enc_size, None);
+            let (_, decompressed) = key_block_parser(block.len(), data.len(), None)(&block).unwrap();
+            assert_eq!(decompressed, data, "{} {} {}", comp_method, enc_method, enc_size);
+        }
+    }
 
-            let data: Vec<u8> = match enc_method {
-                0 => Vec::from(encrypted),
-                1 => fast_decrypt(encrypted, key.as_slice()),
-                2 => {
-                    let decrypt = vec![];
-                    decrypt
-                }
-                _ => panic!("unknown enc method: {}", enc_method),
-            };
-
-            let decompressed = match comp_method {
-                0 => data,
-                1 => {
-                    let mut comp: Vec<u8> = vec![0xf0];
-                    comp.extend_from_slice(&data[..]);
-                    let lzo = minilzo_rs::LZO::init().unwrap();
-                    lzo.decompress(&data[..], dsize).unwrap()
-                }
-                2 => {
-                    let mut v = vec![];
-                    ZlibDecoder::new(&data[..]).read_to_end(&mut v).unwrap();
-                    v
-                }
-                _ => panic!("unknown compression method: {}", comp_method),
-            };
-
-            decompressed
-        },
-    )
+    #[test]
+    fn block_smaller_than_its_header() {
+        let block = encrypt_block(b"0000000\x00apple\x00", 0, 0, 0, None);
+        for csize in 0..8 {
+            let result = key_block_parser(csize, 14, None)(&block);
+            assert!(matches!(result, Err(nom::Err::Failure(MdictError::Malformed(_)))), "{}", csize);
+        }
+    }
+
+    #[test]
+    fn truncated_key_block_info() {
+        let entries: Vec<(String, String)> = (0..200).map(|i| (format!("w{:03}", i), format!("def {}", i))).collect();
+        let mut data = vec![];
+        MdxWriter::new("info").block_size(64).write(entries, &mut data).unwrap();
+        let (rest, header) = parse_header(&data).unwrap();
+        let (rest, kbh) = parse_key_block_header(rest, &header).unwrap();
+        assert!(parse_key_block_info(rest, &kbh, &header).is_ok());
+
+        for n in 0..kbh.key_block_info_len {
+            assert!(parse_key_block_info(&rest[..n], &kbh, &header).is_err(), "{}", n);
+        }
+        // key block header 中的解压后大小比实际小, 解压出的数据不完整
+        let short = KeyBlockHeader {
+            key_block_info_decompressed_len: kbh.key_block_info_decompressed_len - 1,
+            ..kbh
+        };
+        assert!(parse_key_block_info(rest, &short, &header).is_err());
+        // 解压后大小超过压缩比, 不分配内存直接拒绝
+        let oversized = KeyBlockHeader {
+            key_block_info_decompressed_len: usize::MAX,
+            ..short
+        };
+        let result = parse_key_block_info(rest, &oversized, &header);
+        assert!(matches!(result, Err(nom::Err::Failure(MdictError::Malformed(_)))));
+    }
 }
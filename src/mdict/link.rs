@@ -0,0 +1,151 @@
+use std::collections::HashSet;
+
+use log::warn;
+use serde_derive::Serialize;
+
+/// 最多跟随几次 @@@LINK= 跳转
+pub const MAX_LINK_DEPTH: usize = 8;
+
+const LINK_PREFIX: &str = "@@@LINK=";
+
+/// 一次 @@@LINK= 跳转
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Redirect {
+    pub from: String,
+    pub to: String,
+}
+
+/// 跟随 @@@LINK= 之后的查词结果
+/// definitions: 所有最终的释义, 按查到的顺序
+/// redirects: 依次跟随的跳转
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Resolved {
+    pub definitions: Vec<String>,
+    pub redirects: Vec<Redirect>,
+}
+
+/// 释义是 `@@@LINK=word` 时返回跳转的目标
+pub fn link_target(definition: &str) -> Option<&str> {
+    definition
+        .trim_start()
+        .strip_prefix(LINK_PREFIX)
+        .map(|target| target.trim_end_matches(['\0', '\r', '\n']).trim())
+}
+
+/// 查词并跟随 @@@LINK= 跳转, lookup 在同一个词典中查一个词的所有释义
+/// 跳转形成环或者超过 MAX_LINK_DEPTH 时停止跟随, 这条释义被丢弃
+pub fn resolve_links<E, F>(word: &str, mut lookup: F) -> Result<Resolved, E>
+where
+    F: FnMut(&str) -> Result<Vec<String>, E>,
+{
+    let mut resolved = Resolved::default();
+    let mut visited = HashSet::from([word.to_string()]);
+    follow(word, 0, &mut lookup, &mut visited, &mut resolved)?;
+    Ok(resolved)
+}
+
+fn follow<E, F>(
+    word: &str,
+    depth: usize,
+    lookup: &mut F,
+    visited: &mut HashSet<String>,
+    resolved: &mut Resolved,
+) -> Result<(), E>
+where
+    F: FnMut(&str) -> Result<Vec<String>, E>,
+{
+    for definition in lookup(word)? {
+        let Some(target) = link_target(&definition) else {
+            resolved.definitions.push(definition);
+            continue;
+        };
+        if depth >= MAX_LINK_DEPTH {
+            warn!("too many @@@LINK redirects: {} -> {}", word, target);
+            continue;
+        }
+        // 已经查过的词不再查, 既避免环也避免重复的释义
+        if !visited.insert(target.to_string()) {
+            warn!("@@@LINK redirect cycle: {} -> {}", word, target);
+            continue;
+        }
+        resolved.redirects.push(Redirect {
+            from: word.to_string(),
+            to: target.to_string(),
+        });
+        follow(target, depth + 1, lookup, visited, resolved)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+
+    use super::*;
+
+    fn resolve(dict: &[(&str, &str)], word: &str) -> Resolved {
+        let mut map: HashMap<&str, Vec<String>> = HashMap::new();
+        for (key, definition) in dict {
+            map.entry(*key).or_default().push(definition.to_string());
+        }
+        let lookup = |w: &str| Ok::<_, Infallible>(map.get(w).cloned().unwrap_or_default());
+        resolve_links(word, lookup).unwrap()
+    }
+
+    fn redirect(from: &str, to: &str) -> Redirect {
+        Redirect { from: from.to_string(), to: to.to_string() }
+    }
+
+    #[test]
+    fn link_cycle_stops() {
+        let resolved = resolve(&[("a", "@@@LINK=b"), ("b", "@@@LINK=a")], "a");
+        assert!(resolved.definitions.is_empty());
+        assert_eq!(resolved.redirects, vec![redirect("a", "b")]);
+    }
+
+    #[test]
+    fn self_link_stops() {
+        let resolved = resolve(&[("a", "@@@LINK=a\r\n\0")], "a");
+        assert!(resolved.definitions.is_empty());
+        assert!(resolved.redirects.is_empty());
+    }
+
+    #[test]
+    fn chain_longer_than_max_depth_is_dropped() {
+        let words: Vec<String> = (0..=MAX_LINK_DEPTH + 1).map(|i| format!("w{}", i)).collect();
+        let links: Vec<String> = words[1..].iter().map(|w| format!("@@@LINK={}", w)).collect();
+        let mut dict: Vec<(&str, &str)> = words.iter().zip(&links).map(|(w, l)| (w.as_str(), l.as_str())).collect();
+        dict.push((words.last().unwrap(), "end"));
+
+        // w0 -> w9 需要 MAX_LINK_DEPTH + 1 次跳转
+        let resolved = resolve(&dict, "w0");
+        assert!(resolved.definitions.is_empty());
+        assert_eq!(resolved.redirects.len(), MAX_LINK_DEPTH);
+
+        // w1 -> w9 正好 MAX_LINK_DEPTH 次跳转
+        let resolved = resolve(&dict, "w1");
+        assert_eq!(resolved.definitions, vec!["end"]);
+        assert_eq!(resolved.redirects.len(), MAX_LINK_DEPTH);
+    }
+
+    #[test]
+    fn homographs_mix_links_and_definitions() {
+        let dict = [
+            ("bank", "river side"),
+            ("bank", "@@@LINK=banking"),
+            ("bank", "@@@LINK=bank"),
+            ("banking", "money"),
+            ("banking", "@@@LINK=bank"),
+        ];
+        let resolved = resolve(&dict, "bank");
+        assert_eq!(resolved.definitions, vec!["river side", "money"]);
+        assert_eq!(resolved.redirects, vec![redirect("bank", "banking")]);
+    }
+
+    #[test]
+    fn link_target_trims_record_terminator() {
+        assert_eq!(link_target(" @@@LINK=apple \r\n\0"), Some("apple"));
+        assert_eq!(link_target("apple"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::mdict::error::MdictError;
+use crate::mdict::header::{parse_header, Header, Version};
+use crate::mdict::mdx::{parse_records, RecordOffset};
+use crate::mdict::passcode::Passcode;
+use crate::mdict::source::{map_file, range_in, Source};
+
+/// MDD 资源文件, 存放词典用到的图片、音频、字体和css等
+/// 文件结构和mdx相同, 区别在于:
+/// key: utf-16le编码的资源路径, 以`\`分隔, 例如 `\uk_pron.png`
+/// record: 资源文件的原始bytes
+#[derive(Debug)]
+pub struct Mdd {
+    records_offset: Vec<RecordOffset>,
+    // record block 所在的bytes, 和Mdx一样可以是mmap
+    source: Source,
+    record_block_range: Range<usize>,
+    header: Header,
+    // 小写的资源路径 -> records_offset的下标
+    index: HashMap<String, usize>,
+}
+
+impl Mdd {
+    /// let data = include_bytes!("/file.mdd");
+    /// let mdd = Mdd::new(&data);
+    pub fn new(data: &[u8]) -> Result<Mdd, MdictError> {
+        Mdd::with_passcode(data, None)
+    }
+
+    /// 和mdx使用同一个注册码, 只复制record block到内存
+    pub fn with_passcode(data: &[u8], passcode: Option<&Passcode>) -> Result<Mdd, MdictError> {
+        let (header, records_offset, range) = parse(data, passcode)?;
+        let source = Source::Memory(data[range.clone()].to_vec());
+        Ok(Mdd::from_parts(header, records_offset, source, 0..range.len()))
+    }
+
+    /// 使用mmap打开mdd文件, 资源的bytes用到时才从文件读取
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Mdd, MdictError> {
+        Mdd::open_with_passcode(path, None)
+    }
+
+    /// 使用mmap打开Encrypted="1"的mdd文件
+    pub fn open_with_passcode<P: AsRef<Path>>(path: P, passcode: Option<&Passcode>) -> Result<Mdd, MdictError> {
+        let mmap = map_file(path)?;
+        let (header, records_offset, range) = parse(&mmap, passcode)?;
+        Ok(Mdd::from_parts(header, records_offset, Source::Mmap(mmap), range))
+    }
+
+    fn from_parts(
+        header: Header,
+        records_offset: Vec<RecordOffset>,
+        source: Source,
+        record_block_range: Range<usize>,
+    ) -> Mdd {
+        let index = records_offset
+            .iter()
+            .enumerate()
+            .map(|(i, rs)| (rs.text().to_lowercase(), i))
+            .collect();
+        Mdd {
+            records_offset,
+            source,
+            record_block_range,
+            header,
+            index,
+        }
+    }
+
+    /// 根据资源路径查找资源的bytes, `uk_pron.png` `/img/a.png` `\img\a.png` 都可以
+    pub fn get(&self, path: &str) -> Result<Option<Vec<u8>>, MdictError> {
+        let key = resource_key(path);
+        match self.index.get(&key) {
+            Some(i) => self.records_offset[*i]
+                .read_bytes(&self.source[self.record_block_range.clone()], self.header.record_key.as_deref())
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// title description 等header中的信息
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// 所有资源的定位信息, 按文件中的顺序
+    pub fn entries(&self) -> std::slice::Iter<'_, RecordOffset> {
+        self.records_offset.iter()
+    }
+}
+
+/// 解析header和所有key block, 返回record block在data中的位置
+fn parse(data: &[u8], passcode: Option<&Passcode>) -> Result<(Header, Vec<RecordOffset>, Range<usize>), MdictError> {
+    let (rest, mut header) = parse_header(data)?;
+    // v1 v2 mdd的key总是utf-16le编码, 不管header中的Encoding是什么; v3 按header中的Encoding, 默认utf-8
+    if !matches!(header.version, Version::V3) {
+        header.encoding = "UTF-16LE".to_string();
+    }
+    if let Some(passcode) = passcode {
+        header.record_key = Some(passcode.record_key(&header.register_by)?);
+    }
+    let blocks = parse_records(rest, &header)?;
+    let range = range_in(data, blocks.record_block_buf);
+    Ok((header, blocks.records_offset, range))
+}
+
+/// 资源路径转换成mdd中的key: 统一使用`\`分隔并以`\`开头, 不区分大小写
+fn resource_key(path: &str) -> String {
+    let key = path.replace('/', "\\").to_lowercase();
+    if key.starts_with('\\') {
+        key
+    } else {
+        format!("\\{}", key)
+    }
+}
@@ -1,8 +1,30 @@
-use crate::mdict::header::parse_header;
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::path::Path;
+use std::slice::ChunkBy;
+
+use adler32::adler32;
+use encoding::DecoderTrap;
+use nom::bytes::complete::take;
+use nom::multi::{length_data, many0};
+use nom::number::complete::{be_u32, be_u64};
+use nom::sequence::tuple;
+use rayon::prelude::*;
+
+use crate::mdict::cache::{BlockCache, BlockId, CacheStats, DEFAULT_CACHE_CAPACITY};
+use crate::mdict::error::{MdictError, ParseResult};
+use crate::mdict::header::{parse_header, Header, Version};
+use crate::mdict::link::{resolve_links, Resolved};
+use crate::mdict::passcode::Passcode;
 use crate::mdict::keyblock::{
-    Entry, parse_key_block_header, parse_key_block_info, parse_key_blocks,
+    Entry, KeyBlockHeader, KeyBlockSize, parse_key_block_header, parse_key_block_info, parse_key_blocks,
+    decompress_key_block, parse_key_block_entries, parse_key_block_sizes_v3,
 };
+use crate::mdict::source::{map_file, range_in, Source};
 use crate::mdict::recordblock::{parse_record_blocks, record_block_parser, RecordBlockSize};
+use crate::mdict::stylesheet::StyleSheet;
+use crate::mdict::verify::VerifyReport;
+use crate::util::{block_checksum, decrypt_block_data, text_decoder};
 
 /// 一个record的定位信息：在buf中的offset和在block解压后的offset
 /// draw with: https://asciiflow.com/#/
@@ -22,7 +44,7 @@ use crate::mdict::recordblock::{parse_record_blocks, record_block_parser, Record
 //
 #[derive(Debug)]
 pub struct RecordOffset {
-    pub(crate) text: String,
+    text: String,
     // record所在block在buf的offset 截取block使用
     block_start_in_buf: usize,
     // 解析block使用
@@ -34,10 +56,28 @@ pub struct RecordOffset {
 }
 
 // todo: why can not be String?
+/// 一条释义, 由 Mdx::items 得到
 #[derive(Debug)]
 pub struct Record<'a> {
-    pub(crate) text: &'a str,
-    pub(crate) definition: String,
+    text: &'a str,
+    definition: String,
+}
+
+impl<'a> Record<'a> {
+    /// key block中的key
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    /// 解码并展开StyleSheet后的释义
+    pub fn definition(&self) -> &str {
+        &self.definition
+    }
+
+    /// 取出释义, 不复制
+    pub fn into_definition(self) -> String {
+        self.definition
+    }
 }
 
 /// MDX 详细结构见 https://bitbucket.org/xwang/mdict-analysis/src/master/MDX.svg
@@ -53,76 +93,568 @@ pub struct Record<'a> {
 /// record: 是一条释义
 #[derive(Debug)]
 pub struct Mdx {
-    pub records_offset: Vec<RecordOffset>,
-    pub record_block_buf: Vec<u8>,
-    pub encoding: String,
-    pub encrypted: String,
+    records_offset: Vec<RecordOffset>,
+    // title description encoding 等header中的信息
+    header: Header,
+    // 由header中的StyleSheet解析得到, 用于展开释义中的 `N`
+    stylesheet: StyleSheet,
+    // lookup 使用: key block info 和 record block info
+    key_block_header: Option<KeyBlockHeader>,
+    key_blocks: Vec<KeyBlockSize>,
+    record_blocks: Vec<RecordBlockSize>,
+    // key block 和 record block 所在的bytes, 只记录它们在source中的位置, 用到时再读取
+    source: Source,
+    key_block_range: Range<usize>,
+    record_block_range: Range<usize>,
+    // 查词时解压的key block和record block
+    cache: BlockCache,
+}
+
+/// 解析得到的索引, key block 和 record block 只记录在文件中的位置
+/// 和bytes分开, Mdx::new 可以在解析之后只复制block所在的部分
+struct Layout {
+    header: Header,
+    records_offset: Vec<RecordOffset>,
+    key_block_header: Option<KeyBlockHeader>,
+    key_blocks: Vec<KeyBlockSize>,
+    record_blocks: Vec<RecordBlockSize>,
+    key_block_range: Range<usize>,
+    record_block_range: Range<usize>,
+}
+
+impl Layout {
+    /// parse: parse_records 解压所有key block得到entries, parse_block_tables 只解析block的索引
+    fn parse<F>(data: &[u8], passcode: Option<&Passcode>, parse: F) -> Result<Layout, MdictError>
+    where
+        F: for<'a> FnOnce(&'a [u8], &Header) -> Result<Blocks<'a>, MdictError>,
+    {
+        let (rest, mut header) = parse_header(data)?;
+        if let Some(passcode) = passcode {
+            header.record_key = Some(passcode.record_key(&header.register_by)?);
+        }
+        let blocks = parse(rest, &header)?;
+        Ok(Layout {
+            key_block_range: range_in(data, blocks.key_block_buf),
+            record_block_range: range_in(data, blocks.record_block_buf),
+            header,
+            records_offset: blocks.records_offset,
+            key_block_header: blocks.key_block_header,
+            key_blocks: blocks.key_blocks,
+            record_blocks: blocks.record_blocks,
+        })
+    }
+
+    /// 从key block到record block结束, 中间只有很小的record block info
+    fn block_range(&self) -> Range<usize> {
+        self.key_block_range.start.min(self.record_block_range.start)
+            ..self.key_block_range.end.max(self.record_block_range.end)
+    }
+
+    /// source 从文件中的 start 开始
+    fn into_mdx(self, source: Source, start: usize) -> Mdx {
+        let shift = |r: Range<usize>| r.start - start..r.end - start;
+        Mdx {
+            records_offset: self.records_offset,
+            stylesheet: StyleSheet::parse(&self.header.stylesheet),
+            header: self.header,
+            key_block_header: self.key_block_header,
+            key_blocks: self.key_blocks,
+            record_blocks: self.record_blocks,
+            source,
+            key_block_range: shift(self.key_block_range),
+            record_block_range: shift(self.record_block_range),
+            cache: BlockCache::new(DEFAULT_CACHE_CAPACITY),
+        }
+    }
 }
 
 impl Mdx {
     /// let data = include_bytes!("/file.mdx");
     /// let mdx = Mdx::new(&data);
-    pub fn new(data: &[u8]) -> Mdx {
-        let (data, header) = parse_header(data).unwrap();
+    pub fn new(data: &[u8]) -> Result<Mdx, MdictError> {
+        Mdx::with_passcode(data, None)
+    }
 
-        let (data, kbh) = parse_key_block_header(data, &header).unwrap();
-        let (data, key_blocks_size) =
-            parse_key_block_info(data, kbh.key_block_info_len, &header).unwrap();
-        let (data, entries) =
-            parse_key_blocks(data, kbh.key_blocks_len, &header, &key_blocks_size).unwrap();
-        let (data, record_blocks_size) = parse_record_blocks(data, &header).unwrap();
+    /// Encrypted="1" 的词典需要注册码和注册时使用的email或device id
+    pub fn with_passcode(data: &[u8], passcode: Option<&Passcode>) -> Result<Mdx, MdictError> {
+        let layout = Layout::parse(data, passcode, parse_records)?;
+        let range = layout.block_range();
+        let source = Source::Memory(data[range.clone()].to_vec());
+        Ok(layout.into_mdx(source, range.start))
+    }
 
-        //计算position耗时，一次计算就保存下来
-        let offset: Vec<RecordOffset> = records_offset(&entries, &record_blocks_size);
+    /// 使用mmap打开mdx文件, 内存中只保留key索引和block的位置信息
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Mdx, MdictError> {
+        Mdx::open_with_passcode(path, None)
+    }
 
-        Mdx {
-            records_offset: offset,
-            record_block_buf: Vec::from(data),
-            encoding: header.encoding,
-            encrypted: header.encrypted,
-        }
+    /// 使用mmap打开Encrypted="1"的mdx文件
+    pub fn open_with_passcode<P: AsRef<Path>>(
+        path: P,
+        passcode: Option<&Passcode>,
+    ) -> Result<Mdx, MdictError> {
+        let mmap = map_file(path)?;
+        let layout = Layout::parse(&mmap, passcode, parse_records)?;
+        Ok(layout.into_mdx(Source::Mmap(mmap), 0))
+    }
+
+    /// title description encoding 等header中的信息
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// 所有record block的bytes
+    fn record_block_buf(&self) -> &[u8] {
+        &self.source[self.record_block_range.clone()]
+    }
+
+    fn key_block_buf(&self) -> &[u8] {
+        &self.source[self.key_block_range.clone()]
+    }
+
+    /// 设置block缓存的大小(解压后的bytes), 0表示不缓存
+    pub fn set_cache_capacity(&self, capacity: usize) {
+        self.cache.set_capacity(capacity);
+    }
+
+    /// block缓存的命中次数和当前大小
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
     }
 
-    #[allow(unused)]
-    pub fn entries(&self) -> impl Iterator<Item=&RecordOffset> {
-        return self.records_offset.iter();
+    /// 所有entry的定位信息, 按文件中的顺序
+    pub fn entries(&self) -> std::slice::Iter<'_, RecordOffset> {
+        self.records_offset.iter()
     }
 
-    pub fn items(&self) -> impl Iterator<Item=Record> {
-        self.records_offset.iter().map(|rs| {
-            let def = self.find_definition(&rs);
-            Record {
-                text: &rs.text,
-                definition: def,
+    /// 按block遍历所有record, 每个record block只解压一次
+    /// 遍历时不经过缓存, 避免把查词时缓存的block都挤出去
+    pub fn items(&self) -> Items<'_> {
+        Items {
+            mdx: self,
+            blocks: self
+                .records_offset
+                .chunk_by(|a, b| a.block_start_in_buf == b.block_start_in_buf),
+            records: VecDeque::new(),
+        }
+    }
+
+    /// 不经过sqlite直接查词
+    /// 二分查找key所在的key block, 只解压这个key block和record所在的record block
+    /// 按header中的 KeyCaseSensitive 和 StripKey 比较, e.g. "Apple" 可以查到 "apple"
+    pub fn lookup(&self, word: &str) -> Result<Vec<String>, MdictError> {
+        let target = self.key_form(word);
+        let start = self
+            .key_blocks
+            .partition_point(|block| self.key_form(&block.last_key) < target);
+
+        let mut definitions = vec![];
+        // 同一个key的多条释义可能跨越多个key block
+        for i in start..self.key_blocks.len() {
+            if self.key_form(&self.key_blocks[i].first_key) > target {
+                break;
+            }
+            let entries = self.read_key_block(i)?;
+            for (j, entry) in entries.iter().enumerate() {
+                if self.key_form(&entry.text) != target {
+                    continue;
+                }
+                let end = match entries.get(j + 1) {
+                    Some(next) => next.record_start_in_de_buf,
+                    None => self.next_record_start(i)?,
+                };
+                let rs = self.record_offset(entry, end)?;
+                definitions.push(self.definition(&rs)?);
             }
+        }
+        Ok(definitions)
+    }
+
+    /// 查词并跟随 @@@LINK= 跳转, 返回最终的释义和依次跟随的跳转
+    pub fn resolve(&self, word: &str) -> Result<Resolved, MdictError> {
+        resolve_links(word, |w| self.lookup(w))
+    }
+
+    /// key block 是按这个形式排序的, 查词时也按这个形式比较
+    fn key_form(&self, key: &str) -> String {
+        self.header.normalize_key(key)
+    }
+
+    /// 第i个key block之后的第一个record的offset, 没有下一个block时就是整个record buf解压后的大小
+    fn next_record_start(&self, i: usize) -> Result<usize, MdictError> {
+        match self.key_blocks.get(i + 1) {
+            Some(_) => self
+                .read_key_block(i + 1)?
+                .first()
+                .map(|entry| entry.record_start_in_de_buf)
+                .ok_or_else(|| MdictError::Malformed("empty key block".to_string())),
+            None => Ok(self
+                .record_blocks
+                .last()
+                .map_or(0, |block| block.de_offset + block.dsize)),
+        }
+    }
+
+    /// 解压第i个key block, 经过缓存
+    fn read_key_block(&self, i: usize) -> Result<Vec<Entry>, MdictError> {
+        let block = &self.key_blocks[i];
+        let decompressed = self.cache.get_or_insert_with(BlockId::Key(block.offset), || {
+            decompress_key_block(self.key_block_buf(), block, &self.header)
+        })?;
+        parse_key_block_entries(&decompressed, &self.header)
+    }
+
+    /// 由entry在整个解压buf中的offset找到record所在的record block
+    fn record_offset(&self, entry: &Entry, end: usize) -> Result<RecordOffset, MdictError> {
+        let start = entry.record_start_in_de_buf;
+        let i = self
+            .record_blocks
+            .partition_point(|block| block.de_offset + block.dsize <= start);
+        let block = self
+            .record_blocks
+            .get(i)
+            .ok_or_else(|| MdictError::Malformed(format!("record {} out of buf", entry.text)))?;
+        Ok(RecordOffset {
+            text: entry.text.clone(),
+            block_start_in_buf: block.offset,
+            block_csize: block.csize,
+            block_dsize: block.dsize,
+            record_start_in_de_block: start - block.de_offset,
+            record_end_in_de_block: end.saturating_sub(block.de_offset).min(block.dsize),
         })
     }
 
-    fn find_definition(&self, rs: &RecordOffset) -> String {
+    /// 和 verify 一样检查mdx文件, 但打开时只解析header和block的索引, 不解压key block
+    /// key block 损坏时 Mdx::open 会失败, 这里把每个block的问题都记录在结果中
+    /// header 或 block 索引无法解析时返回错误
+    pub fn verify_file<P: AsRef<Path>>(path: P, passcode: Option<&Passcode>) -> Result<VerifyReport, MdictError> {
+        let mmap = map_file(path)?;
+        let layout = Layout::parse(&mmap, passcode, parse_block_tables)?;
+        Ok(layout.into_mdx(Source::Mmap(mmap), 0).verify())
+    }
+
+    /// 检查所有key block和record block: checksum, 解压后的大小, key的顺序, entry的个数
+    /// 解压整个文件, 比较慢
+    pub fn verify(&self) -> VerifyReport {
+        let mut report = VerifyReport {
+            key_blocks: self.key_blocks.len(),
+            record_blocks: self.record_blocks.len(),
+            ..Default::default()
+        };
+
+        let mut all_entries = vec![];
+        let mut prev_key: Option<String> = None;
+        for (i, block) in self.key_blocks.iter().enumerate() {
+            let part = format!("key block {}", i);
+            let decompressed = match decompress_key_block(self.key_block_buf(), block, &self.header) {
+                Ok(decompressed) => decompressed,
+                Err(e) => {
+                    report.problem(&part, e.to_string());
+                    continue;
+                }
+            };
+            let block_buf = self.key_block_buf().get(block.offset..).unwrap_or_default();
+            self.verify_block(&mut report, &part, block_buf, block.csize, block.dsize, &decompressed);
+
+            let entries = match parse_key_block_entries(&decompressed, &self.header) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    report.problem(&part, e.to_string());
+                    continue;
+                }
+            };
+            // v3 没有 key block info, entry个数和first/last key本来就是解压得到的
+            let has_block_info = !matches!(self.header.version, Version::V3);
+            if has_block_info && entries.len() != block.entries_num {
+                report.problem(&part, format!("{} entries, key block info says {}", entries.len(), block.entries_num));
+            }
+            let first = entries.first().map_or("", |e| e.text.as_str());
+            let last = entries.last().map_or("", |e| e.text.as_str());
+            if has_block_info && (first != block.first_key || last != block.last_key) {
+                report.problem(
+                    &part,
+                    format!(
+                        "first/last key {:?}/{:?}, key block info says {:?}/{:?}",
+                        first, last, block.first_key, block.last_key
+                    ),
+                );
+            }
+            // key block 和 lookup 的二分查找依赖key的顺序
+            for entry in &entries {
+                let key = self.key_form(&entry.text);
+                if prev_key.as_ref().is_some_and(|prev| key < *prev) {
+                    report.problem(&part, format!("key {:?} is out of order", entry.text));
+                }
+                prev_key = Some(key);
+            }
+            all_entries.extend(entries);
+        }
+        let entries_num = all_entries.len();
+        report.entries = entries_num;
+
+        if let Some(kbh) = &self.key_block_header {
+            if kbh.block_num != self.key_blocks.len() {
+                report.problem(
+                    "key block header",
+                    format!("{} key blocks, header says {}", self.key_blocks.len(), kbh.block_num),
+                );
+            }
+            if kbh.entry_num != entries_num {
+                report.problem(
+                    "key block header",
+                    format!("{} entries, header says {}", entries_num, kbh.entry_num),
+                );
+            }
+        }
+        let located = records_offset(&all_entries, &self.record_blocks).len();
+        if located != entries_num {
+            report.problem(
+                "record blocks",
+                format!("{} of {} entries are in record blocks", located, entries_num),
+            );
+        }
+
+        for (i, block) in self.record_blocks.iter().enumerate() {
+            let part = format!("record block {}", i);
+            let Some(block_buf) = self.record_block_buf().get(block.offset..) else {
+                report.problem(&part, MdictError::Truncated.to_string());
+                continue;
+            };
+            match record_block_parser(block.csize, block.dsize, self.header.record_key.clone())(block_buf) {
+                Ok((_, decompressed)) => {
+                    self.verify_block(&mut report, &part, block_buf, block.csize, block.dsize, &decompressed)
+                }
+                Err(e) => report.problem(&part, MdictError::from(e).to_string()),
+            }
+        }
+        report
+    }
+
+    /// 解压后的大小和checksum
+    /// v1 v2 的checksum是解压后数据的adler32, v3 是block中解密后, 解压前数据的adler32
+    fn verify_block(
+        &self,
+        report: &mut VerifyReport,
+        part: &str,
+        block_buf: &[u8],
+        csize: usize,
+        dsize: usize,
+        decompressed: &[u8],
+    ) {
+        if decompressed.len() != dsize {
+            report.problem(part, format!("decompressed size {}, block info says {}", decompressed.len(), dsize));
+        }
+        let decrypted;
+        let data = match self.header.version {
+            Version::V3 => match decrypt_block_data(block_buf, csize, self.header.record_key.as_deref()) {
+                Ok(data) => {
+                    decrypted = data;
+                    &decrypted
+                }
+                Err(e) => {
+                    report.problem(part, e.to_string());
+                    return;
+                }
+            },
+            Version::V1 | Version::V2 => decompressed,
+        };
+        let expected = block_checksum(block_buf).unwrap_or_default();
+        let actual = adler32(data).unwrap();
+        if expected != actual {
+            report.problem(part, format!("checksum mismatch: expected {:#010x}, actual {:#010x}", expected, actual));
+        }
+    }
+
+    /// 读取一个entry的释义, entry 来自 Mdx::entries
+    pub fn definition(&self, rs: &RecordOffset) -> Result<String, MdictError> {
+        let block = self.cache.get_or_insert_with(BlockId::Record(rs.block_start_in_buf), || {
+            rs.read_block(self.record_block_buf(), self.header.record_key.as_deref())
+        })?;
+        self.decode_definition(rs.slice(&block)?)
+    }
+
+    /// record bytes 按header中的Encoding解码, 并展开StyleSheet
+    fn decode_definition(&self, record_decompressed: &[u8]) -> Result<String, MdictError> {
+        let decoder = text_decoder(&self.header.encoding)?;
+        let definition = decoder
+            .decode(record_decompressed, DecoderTrap::Replace)
+            .map_err(|e| MdictError::BadEncoding(e.to_string()))?;
+        // record以 \0 (utf-16是 \0\0) 结尾, 有的词典还会多一个 \r\n
+        let definition = definition.trim_end_matches(['\0', '\r', '\n']);
+        Ok(self.stylesheet.apply(definition))
+    }
+}
+
+/// Mdx::items 返回的迭代器, 每个record block只解压一次
+/// 每次取出若干个block在多个线程中解压和解码, 返回的顺序和文件中的顺序相同
+pub struct Items<'a> {
+    mdx: &'a Mdx,
+    // 同一个record block中的entries
+    blocks: ChunkBy<'a, RecordOffset, fn(&RecordOffset, &RecordOffset) -> bool>,
+    // 已经解码但还没有返回的records
+    records: VecDeque<Result<Record<'a>, MdictError>>,
+}
+
+impl<'a> Items<'a> {
+    /// 解压一个block并解码其中所有的record
+    fn read_records(&self, records: &'a [RecordOffset]) -> Vec<Result<Record<'a>, MdictError>> {
+        let block = records[0].read_block(
+            self.mdx.record_block_buf(),
+            self.mdx.header.record_key.as_deref(),
+        );
+        records
+            .iter()
+            .map(|rs| {
+                let block = block.as_ref().map_err(Clone::clone)?;
+                let definition = self.mdx.decode_definition(rs.slice(block)?)?;
+                Ok(Record {
+                    text: &rs.text,
+                    definition,
+                })
+            })
+            .collect()
+    }
+}
+
+impl<'a> Iterator for Items<'a> {
+    type Item = Result<Record<'a>, MdictError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.records.is_empty() {
+            // 每个线程几个block, 不一次解压整个文件
+            let batch: Vec<&'a [RecordOffset]> =
+                self.blocks.by_ref().take(rayon::current_num_threads() * 4).collect();
+            let decoded: Vec<_> = batch.par_iter().map(|records| self.read_records(records)).collect();
+            self.records.extend(decoded.into_iter().flatten());
+        }
+        self.records.pop_front()
+    }
+}
+
+impl RecordOffset {
+    /// key block中的key
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// 解压record所在的block, 截取出record的bytes
+    pub(crate) fn read_bytes(
+        &self,
+        record_block_buf: &[u8],
+        record_key: Option<&[u8]>,
+    ) -> Result<Vec<u8>, MdictError> {
+        let block_decompressed = self.read_block(record_block_buf, record_key)?;
+        self.slice(&block_decompressed).map(Vec::from)
+    }
+
+    /// 解压record所在的block
+    pub(crate) fn read_block(
+        &self,
+        record_block_buf: &[u8],
+        record_key: Option<&[u8]>,
+    ) -> Result<Vec<u8>, MdictError> {
         // block bytes with tail
-        let block_buf = &self.record_block_buf[rs.block_start_in_buf..];
+        let block_buf = record_block_buf
+            .get(self.block_start_in_buf..)
+            .ok_or(MdictError::Truncated)?;
 
         let (_, block_decompressed) =
-            record_block_parser(rs.block_csize, rs.block_dsize)(block_buf).unwrap();
+            record_block_parser(self.block_csize, self.block_dsize, record_key.map(Vec::from))(block_buf)?;
+        Ok(block_decompressed)
+    }
 
-        let record_decompressed =
-            &block_decompressed[rs.record_start_in_de_block..rs.record_end_in_de_block];
+    /// 从解压后的block中截取出record的bytes
+    pub(crate) fn slice<'b>(&self, block_decompressed: &'b [u8]) -> Result<&'b [u8], MdictError> {
+        block_decompressed
+            .get(self.record_start_in_de_block..self.record_end_in_de_block)
+            .ok_or_else(|| MdictError::Malformed(format!("record {} out of block", self.text)))
+    }
+}
+
+/// parse_records 的结果
+/// records_offset: 每个record的定位信息
+/// key_blocks record_blocks: block的索引, 直接查词时使用
+pub(crate) struct Blocks<'a> {
+    pub records_offset: Vec<RecordOffset>,
+    // v3 没有 key block header
+    pub key_block_header: Option<KeyBlockHeader>,
+    pub key_blocks: Vec<KeyBlockSize>,
+    pub key_block_buf: &'a [u8],
+    pub record_blocks: Vec<RecordBlockSize>,
+    pub record_block_buf: &'a [u8],
+}
 
-        let def = String::from_utf8_lossy(record_decompressed).to_string();
+/// mdx和mdd共用的解析流程: key block header -> key block info -> key blocks -> record blocks
+pub(crate) fn parse_records<'a>(data: &'a [u8], header: &Header) -> Result<Blocks<'a>, MdictError> {
+    let mut blocks = parse_block_tables(data, header)?;
+    let entries = parse_key_blocks(blocks.key_block_buf, header, &mut blocks.key_blocks)?;
+    //计算position耗时，一次计算就保存下来
+    blocks.records_offset = records_offset(&entries, &blocks.record_blocks);
+    Ok(blocks)
+}
 
-        return def;
+/// 只解析block的索引, 不解压key block, records_offset 为空
+/// Mdx::verify_file 使用, 单个block损坏时不影响检查其他block
+fn parse_block_tables<'a>(data: &'a [u8], header: &Header) -> Result<Blocks<'a>, MdictError> {
+    if let Version::V3 = header.version {
+        return parse_block_tables_v3(data, header);
     }
+
+    let (data, kbh) = parse_key_block_header(data, header)?;
+    let (data, key_blocks) = parse_key_block_info(data, &kbh, header)?;
+    let (data, key_block_buf) = take(kbh.key_blocks_len)(data)?;
+    let (data, record_blocks) = parse_record_blocks(data, header)?;
+    Ok(Blocks {
+        records_offset: vec![],
+        key_block_header: Some(kbh),
+        key_blocks,
+        key_block_buf,
+        record_blocks,
+        record_block_buf: data,
+    })
+}
+
+// v3 section 类型
+const SECTION_RECORD_DATA: u32 = 0x01000000;
+const SECTION_KEY_DATA: u32 = 0x03000000;
+
+/// v3 header之后是若干个section: type(u32) + size(u64) + data
+/// record index 和 key index 两个section是block的索引, 解析时不需要
+fn parse_block_tables_v3<'a>(data: &'a [u8], header: &Header) -> Result<Blocks<'a>, MdictError> {
+    let (_, sections) = parse_sections_v3(data)?;
+    let missing = |name: &str| MdictError::Malformed(format!("v3 {} section not found", name));
+    let key_data = sections
+        .get(&SECTION_KEY_DATA)
+        .ok_or_else(|| missing("key data"))?;
+    let record_data = sections
+        .get(&SECTION_RECORD_DATA)
+        .ok_or_else(|| missing("record data"))?;
+
+    let key_blocks = parse_key_block_sizes_v3(key_data)?;
+    let (_, record_blocks) = parse_record_blocks(record_data, header)?;
+    Ok(Blocks {
+        records_offset: vec![],
+        key_block_header: None,
+        key_blocks,
+        key_block_buf: key_data,
+        record_blocks,
+        record_block_buf: record_data,
+    })
+}
+
+fn parse_sections_v3(data: &[u8]) -> ParseResult<'_, HashMap<u32, &[u8]>> {
+    let (data, sections) = many0(tuple((be_u32, length_data(be_u64))))(data)?;
+    Ok((data, sections.into_iter().collect()))
 }
 
 /// bytes structure: buf -> block -> record(entry)
 fn records_offset(
-    entries: &Vec<Entry>,
-    record_blocks_size: &Vec<RecordBlockSize>,
+    entries: &[Entry],
+    record_blocks_size: &[RecordBlockSize],
 ) -> Vec<RecordOffset> {
     let mut positions: Vec<RecordOffset> = vec![];
     let mut i: usize = 0;
     let mut pre_blocks_dsize_sum = 0;
-    let mut pre_blocks_csize_sum = 0;
     // 同时开始遍历record_blocks_size和entries，每个block包含0或n个entry，当entry的buf_decompressed_offset > pre_blocks_dsize_sum时 说明当前block已经遍历
     for block in record_blocks_size {
         while i < entries.len() {
@@ -133,29 +665,231 @@ fn records_offset(
                 break;
             }
 
-            let mut record_end_in_de_block = 0;
-            if i < entries.len() - 1 {
+            // offset没有按顺序排列的entry得到的 start > end, 读取时返回错误
+            let record_end_in_de_block = if i < entries.len() - 1 {
                 // 计算 record_end_in_decomp_block
                 let next_entry = &entries[i + 1];
-                record_end_in_de_block =
-                    next_entry.record_start_in_de_buf - pre_blocks_dsize_sum;
+                next_entry.record_start_in_de_buf.saturating_sub(pre_blocks_dsize_sum)
             } else {
                 // last entry
-                record_end_in_de_block = block.dsize
-            }
+                block.dsize
+            };
 
             positions.push(RecordOffset {
                 text: entry.text.to_string(),
-                block_start_in_buf: pre_blocks_csize_sum,
+                block_start_in_buf: block.offset,
                 block_csize: block.csize,
                 block_dsize: block.dsize,
-                record_start_in_de_block: entry.record_start_in_de_buf - pre_blocks_dsize_sum,
+                record_start_in_de_block: entry.record_start_in_de_buf.saturating_sub(pre_blocks_dsize_sum),
                 record_end_in_de_block,
             });
             i += 1;
         }
         pre_blocks_dsize_sum += block.dsize;
-        pre_blocks_csize_sum += block.csize;
     }
-    return positions;
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mdict::{Compression, MdxWriter};
+    use crate::util::{compress_block, fast_encrypt, salsa_decrypt};
+
+    use super::*;
+
+    const UUID: &str = "5f0b6c1e-2a3d-4c5e-8f90-a1b2c3d4e5f6";
+    /// UUID 前后两半的xxh64, 由另外实现的xxh64计算得到
+    const UUID_KEY: [u8; 16] = [
+        0x2d, 0xdc, 0x9d, 0xb1, 0xbd, 0x1b, 0x5b, 0x57, 0xb6, 0xfc, 0xe3, 0x19, 0x43, 0x2c, 0x45, 0x4c,
+    ];
+
+    /// v3 block: dsize csize, 然后是 type, 解密后压缩数据的adler32, 数据
+    fn v3_block(data: &[u8], comp_method: u32, enc_method: u32, enc_size: usize) -> Vec<u8> {
+        let compressed = compress_block(data, comp_method).unwrap();
+        let n = enc_size.min(compressed.len());
+        let mut encrypted = match enc_method {
+            1 => fast_encrypt(&compressed[..n], &UUID_KEY),
+            2 => salsa_decrypt(&compressed[..n], &UUID_KEY).unwrap(),
+            _ => compressed[..n].to_vec(),
+        };
+        encrypted.extend_from_slice(&compressed[n..]);
+
+        let mut block = (data.len() as u32).to_be_bytes().to_vec();
+        block.extend_from_slice(&(8 + encrypted.len() as u32).to_be_bytes());
+        block.extend_from_slice(&(comp_method | enc_method << 4 | (enc_size as u32) << 8).to_le_bytes());
+        block.extend_from_slice(&adler32(&compressed[..]).unwrap().to_be_bytes());
+        block.extend(encrypted);
+        block
+    }
+
+    /// block number, total size, blocks
+    fn v3_data(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = (blocks.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(&(blocks.iter().map(Vec::len).sum::<usize>() as u64).to_be_bytes());
+        for block in blocks {
+            data.extend_from_slice(block);
+        }
+        data
+    }
+
+    /// utf-8 header, 然后是 record data, record index, key data, key index 四个section
+    /// 两个key block和两个record block, 其中一个record block用UUID得到的key做Salsa20/8加密
+    fn v3_mdx(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut keys = [vec![], vec![]];
+        let mut records = [vec![], vec![]];
+        let mut offset = 0;
+        for (i, (key, definition)) in entries.iter().enumerate() {
+            let keys = &mut keys[i * 2 / entries.len()];
+            keys.extend_from_slice(&(offset as u64).to_be_bytes());
+            keys.extend_from_slice(key.as_bytes());
+            keys.push(0);
+            let records = &mut records[i * 2 / entries.len()];
+            records.extend_from_slice(definition.as_bytes());
+            records.push(0);
+            offset += definition.len() + 1;
+        }
+        let key_data = v3_data(&[v3_block(&keys[0], 2, 1, 8), v3_block(&keys[1], 0, 0, 0)]);
+        let record_data = v3_data(&[v3_block(&records[0], 2, 2, 16), v3_block(&records[1], 1, 0, 0)]);
+
+        let text = format!(
+            r#"<Dictionary GeneratedByEngineVersion="3.0" RequiredEngineVersion="3.0" Encrypted="0" Encoding="UTF-8" Format="Html" Title="V3" UUID="{}"/>{}"#,
+            UUID, "\r\n\0"
+        );
+        let mut data = (text.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(text.as_bytes());
+        data.extend_from_slice(&adler32(text.as_bytes()).unwrap().to_le_bytes());
+        for (section, body) in [
+            (SECTION_RECORD_DATA, record_data),
+            (0x02000000, b"record index".to_vec()),
+            (SECTION_KEY_DATA, key_data),
+            (0x04000000, b"key index".to_vec()),
+        ] {
+            data.extend_from_slice(&section.to_be_bytes());
+            data.extend_from_slice(&(body.len() as u64).to_be_bytes());
+            data.extend(body);
+        }
+        data
+    }
+
+    #[test]
+    fn read_v3() {
+        let entries = [("apple", "苹果"), ("banana", "香蕉"), ("cherry", "樱桃"), ("date", "枣")];
+        let data = v3_mdx(&entries);
+        let mdx = Mdx::new(&data).unwrap();
+        assert!(matches!(mdx.header().version, Version::V3));
+        assert_eq!(mdx.header().record_key.as_deref(), Some(&UUID_KEY[..]));
+
+        let items: Vec<(String, String)> = mdx
+            .items()
+            .map(|r| r.map(|r| (r.text().to_string(), r.into_definition())))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected: Vec<(String, String)> = entries.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        assert_eq!(items, expected);
+        assert_eq!(mdx.lookup("Banana").unwrap(), vec!["香蕉"]);
+        assert_eq!(mdx.lookup("date").unwrap(), vec!["枣"]);
+        assert!(mdx.lookup("fig").unwrap().is_empty());
+
+        let report = mdx.verify();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!((report.key_blocks, report.record_blocks, report.entries), (2, 2, 4));
+    }
+
+    #[test]
+    fn verify_v3_checksum_after_decryption() {
+        let mut data = v3_mdx(&[("apple", "苹果"), ("banana", "香蕉")]);
+        // 第一个record block 的checksum, 在 header(4 + text + 4), section头(12), block number和total size(12), dsize csize(8), type(4) 之后
+        let text_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let checksum = 4 + text_len + 4 + 12 + 12 + 8 + 4;
+        data[checksum] ^= 0xff;
+        let report = Mdx::new(&data).unwrap().verify();
+        let parts: Vec<&str> = report.problems.iter().map(|p| p.part.as_str()).collect();
+        assert_eq!(parts, vec!["record block 0"]);
+    }
+
+    #[test]
+    fn verify_file_reports_corrupt_key_block() {
+        let entries: Vec<(String, String)> = (0..100).map(|i| (format!("w{:03}", i), format!("def {}", i))).collect();
+        let mut data = vec![];
+        MdxWriter::new("corrupt")
+            .compression(Compression::Zlib)
+            .block_size(64)
+            .write(entries, &mut data)
+            .unwrap();
+
+        // 改掉第一个key block中压缩后的数据
+        let (rest, header) = parse_header(&data).unwrap();
+        let blocks = parse_block_tables(rest, &header).unwrap();
+        let blocks_num = blocks.key_blocks.len();
+        let entries_in_first = blocks.key_blocks[0].entries_num;
+        assert!(blocks_num > 1);
+        let start = range_in(&data, blocks.key_block_buf).start + blocks.key_blocks[0].offset;
+        for b in &mut data[start + 8..start + 12] {
+            *b ^= 0xff;
+        }
+        assert!(Mdx::new(&data).is_err());
+
+        let path = std::env::temp_dir().join(format!("mdict-verify-{}.mdx", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let report = Mdx::verify_file(&path, None);
+        std::fs::remove_file(&path).unwrap();
+
+        // 其他block仍然被检查, 只是少了第一个key block中的entries
+        let report = report.unwrap();
+        assert_eq!(report.key_blocks, blocks_num);
+        assert_eq!(report.entries, 100 - entries_in_first);
+        let parts: Vec<&str> = report.problems.iter().map(|p| p.part.as_str()).collect();
+        assert_eq!(parts, vec!["key block 0", "key block header"]);
+    }
+
+    #[test]
+    fn open_file() {
+        let entries: Vec<(String, String)> = (0..100).map(|i| (format!("w{:03}", i), format!("def {}", i))).collect();
+        let mut data = vec![];
+        MdxWriter::new("open")
+            .compression(Compression::Zlib)
+            .block_size(64)
+            .write(entries, &mut data)
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("mdict-open-{}.mdx", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let mdx = Mdx::open(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        // mmap在文件删除之后仍然可以读取
+        let mdx = mdx.unwrap();
+        assert_eq!(mdx.header().title, "open");
+        assert_eq!(mdx.lookup("w042").unwrap(), vec!["def 42"]);
+        assert_eq!(mdx.lookup("w100").unwrap(), Vec::<String>::new());
+        assert!(mdx.verify().problems.is_empty());
+        // Mdx::new 只复制了block所在的部分, 结果一样
+        let copied = Mdx::new(&data).unwrap();
+        assert_eq!(copied.lookup("w042").unwrap(), vec!["def 42"]);
+        assert!(copied.source.len() < data.len());
+    }
+
+    #[test]
+    fn verify_lzo_decompressed_size() {
+        let entries: Vec<(String, String)> = (0..20).map(|i| (format!("w{:02}", i), format!("def {}", i))).collect();
+        let mut data = vec![];
+        MdxWriter::new("lzo")
+            .compression(Compression::Lzo)
+            .write(entries, &mut data)
+            .unwrap();
+        assert!(Mdx::new(&data).unwrap().verify().is_ok());
+
+        // record block info 在record blocks之前, 每个block是 csize(u64) dsize(u64), 把第一个dsize加大
+        let (rest, header) = parse_header(&data).unwrap();
+        let blocks = parse_block_tables(rest, &header).unwrap();
+        let dsize = blocks.record_blocks[0].dsize;
+        let at = range_in(&data, blocks.record_block_buf).start - 16 * blocks.record_blocks.len() + 8;
+        data[at..at + 8].copy_from_slice(&(dsize as u64 + 16).to_be_bytes());
+
+        // lzo解压出的数据不会补0到dsize
+        let report = Mdx::new(&data).unwrap().verify();
+        let problems: Vec<(&str, &str)> = report.problems.iter().map(|p| (p.part.as_str(), p.message.as_str())).collect();
+        let message = format!("decompressed size {}, block info says {}", dsize, dsize + 16);
+        assert!(problems.contains(&("record block 0", message.as_str())), "{:?}", problems);
+    }
 }
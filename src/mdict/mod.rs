@@ -1,4 +1,34 @@
+mod cache;
+pub(crate) mod error;
+mod fold;
 mod header;
 mod keyblock;
-pub mod mdx;
+mod link;
+mod mdd;
+mod mdx;
+mod passcode;
 mod recordblock;
+mod source;
+mod stylesheet;
+mod verify;
+mod writer;
+
+// 子模块都是私有的, 下面的re-export是mdict唯一的公开接口
+pub use cache::{CacheStats, DEFAULT_CACHE_CAPACITY};
+pub use error::MdictError;
+pub use fold::fold_key;
+pub use header::{Header, Version};
+pub use link::{link_target, resolve_links, Redirect, Resolved, MAX_LINK_DEPTH};
+pub use mdd::Mdd;
+pub use mdx::{Items, Mdx, Record, RecordOffset};
+pub use passcode::Passcode;
+pub use verify::{Problem, VerifyReport};
+pub use writer::{volume_path, Compression, MddWriter, MdxWriter, DEFAULT_BLOCK_SIZE, DEFAULT_VOLUME_SIZE};
+
+// Mdx 和 Mdd 可以在多个线程之间共享
+#[allow(unused)]
+fn assert_send_sync() {
+    fn check<T: Send + Sync>() {}
+    check::<mdx::Mdx>();
+    check::<mdd::Mdd>();
+}
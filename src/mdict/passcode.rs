@@ -0,0 +1,153 @@
+use std::fs;
+use std::path::Path;
+
+use ripemd::{Digest, Ripemd128};
+
+use crate::mdict::error::MdictError;
+use crate::util::salsa_decrypt;
+
+/// 注册信息, 用于打开 Encrypted="1" 的词典
+/// regcode: 词典作者提供的注册码(32位hex), 通常保存在和词典同名的 .key 文件中
+/// user_id: 注册时使用的email或者device id, 由header中的 RegisterBy 决定
+#[derive(Debug, Clone)]
+pub struct Passcode {
+    pub regcode: Vec<u8>,
+    pub user_id: String,
+}
+
+impl Passcode {
+    /// 注册码不是hex时返回 InvalidPasscode
+    pub fn new(regcode: &str, user_id: &str) -> Result<Passcode, MdictError> {
+        Ok(Passcode {
+            regcode: decode_hex(regcode.trim()).ok_or(MdictError::InvalidPasscode)?,
+            user_id: user_id.trim().to_string(),
+        })
+    }
+
+    /// .key 文件: 第一行是注册码, 第二行(可选)是email或device id
+    pub fn from_key_file<P: AsRef<Path>>(path: P, user_id: Option<&str>) -> Result<Passcode, MdictError> {
+        let content = fs::read_to_string(path)?;
+        let mut lines = content.lines().map(str::trim).filter(|l| !l.is_empty());
+        let regcode = lines.next().unwrap_or_default();
+        let user_id = user_id.or(lines.next()).unwrap_or_default();
+        Passcode::new(regcode, user_id)
+    }
+
+    /// 用user id解密注册码得到词典的解密key
+    /// register_by: header中的 RegisterBy, "EMail" 或 "DeviceID"
+    pub fn record_key(&self, register_by: &str) -> Result<Vec<u8>, MdictError> {
+        // 注册码解密后就是16 bytes的key
+        if self.regcode.len() != 16 {
+            return Err(MdictError::InvalidPasscode);
+        }
+        let mut md = Ripemd128::new();
+        if register_by.eq_ignore_ascii_case("email") {
+            let email: Vec<u8> = self
+                .user_id
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes())
+                .collect();
+            md.update(email);
+        } else {
+            md.update(self.user_id.as_bytes());
+        }
+        let user_key = md.finalize();
+        salsa_decrypt(&self.regcode, user_key.as_slice())
+    }
+}
+
+/// 奇数长度或者有非hex字符时返回None
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix 接受 "+1" 这样的输入, 先检查每个字符
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    hex.as_bytes()
+        .chunks(2)
+        .map(|c| u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use adler32::adler32;
+
+    use crate::mdict::{Mdx, MdxWriter};
+
+    use super::*;
+
+    #[test]
+    fn decode_hex_rejects_invalid_input() {
+        assert_eq!(decode_hex("00ff1A"), Some(vec![0x00, 0xff, 0x1a]));
+        assert_eq!(decode_hex("00fg"), None);
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("+1"), None);
+    }
+
+    #[test]
+    fn invalid_regcode_is_an_error() {
+        assert!(matches!(Passcode::new("not hex", "me@example.com"), Err(MdictError::InvalidPasscode)));
+        let passcode = Passcode::new("00112233445566778899aabbccddeeff", " me@example.com ").unwrap();
+        assert_eq!(passcode.regcode.len(), 16);
+        assert_eq!(passcode.user_id, "me@example.com");
+    }
+
+    /// 词典的key是 100..116, 注册码由 readmdict 的算法得到
+    const DICT_KEY: [u8; 16] = [100, 101, 102, 103, 104, 105, 106, 107, 108, 109, 110, 111, 112, 113, 114, 115];
+    const EMAIL_REGCODE: &str = "a62f7dd36e2a7f5ade962c460290aa25";
+    const DEVICE_REGCODE: &str = "67f27836b5c3cdd65117ca82c6dfc08d";
+
+    #[test]
+    fn record_key_from_email_and_device_id() {
+        let passcode = Passcode::new(EMAIL_REGCODE, "me@example.com").unwrap();
+        assert_eq!(passcode.record_key("EMail").unwrap(), DICT_KEY);
+        let passcode = Passcode::new(DEVICE_REGCODE, "ABCD-1234").unwrap();
+        assert_eq!(passcode.record_key("DeviceID").unwrap(), DICT_KEY);
+        // 注册码解密后必须是16 bytes
+        let passcode = Passcode::new("00ff", "me@example.com").unwrap();
+        assert!(matches!(passcode.record_key("EMail"), Err(MdictError::InvalidPasscode)));
+    }
+
+    /// 把MdxWriter写出的 Encrypted="2" 的文件改成 Encrypted="3": key block header 用词典的key做Salsa20/8加密
+    fn encrypted_mdx() -> Vec<u8> {
+        let mut data = vec![];
+        MdxWriter::new("encrypted")
+            .encrypt_key_info(true)
+            .write([("apple", "苹果"), ("banana", "香蕉")], &mut data)
+            .unwrap();
+
+        let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        let text: Vec<u16> = data[4..4 + len].chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        let text = String::from_utf16(&text)
+            .unwrap()
+            .replace(r#"Encrypted="2""#, r#"Encrypted="3""#)
+            .replace(r#"RegisterBy="""#, r#"RegisterBy="EMail""#);
+        let text: Vec<u8> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+
+        let mut out = (text.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(&text);
+        out.extend_from_slice(&adler32(&text[..]).unwrap().to_le_bytes());
+        let rest = &data[4 + len + 4..];
+        out.extend(salsa_decrypt(&rest[..40], &DICT_KEY).unwrap());
+        out.extend_from_slice(&rest[40..]);
+        out
+    }
+
+    #[test]
+    fn open_encrypted_mdx_with_passcode() {
+        let data = encrypted_mdx();
+        assert!(matches!(Mdx::new(&data), Err(MdictError::PasscodeRequired)));
+
+        let passcode = Passcode::new(EMAIL_REGCODE, "me@example.com").unwrap();
+        let mdx = Mdx::with_passcode(&data, Some(&passcode)).unwrap();
+        assert_eq!(mdx.lookup("banana").unwrap(), vec!["香蕉"]);
+        assert!(mdx.verify().is_ok());
+
+        // user id不对时得到错误的key, key block header的checksum不匹配
+        let wrong = Passcode::new(EMAIL_REGCODE, "you@example.com").unwrap();
+        assert!(matches!(
+            Mdx::with_passcode(&data, Some(&wrong)),
+            Err(MdictError::ChecksumMismatch { part: "key block header", .. })
+        ));
+    }
+}
@@ -1,104 +1,192 @@
-use std::io::Read;
-
-use flate2::read::ZlibDecoder;
 use nom::bytes::complete::take;
 use nom::combinator::map;
 use nom::multi::count;
 use nom::number::complete::{be_u32, be_u64, le_u32};
 use nom::sequence::tuple;
-use nom::IResult;
-use ripemd::{Digest, Ripemd128};
 
+use crate::mdict::error::{failure, MdictError, ParseResult};
 use crate::mdict::header::{Header, Version};
-use crate::util::fast_decrypt;
+use crate::util::block_data_size;
+use crate::util::block_key;
+use crate::util::decompress_block;
+use crate::util::decrypt_block;
 
 /// every record block compressed size and decompressed size
 #[derive(Debug)]
 pub struct RecordBlockSize {
     pub csize: usize,
     pub dsize: usize,
+    // block在record block buf中的offset, v3的每个block前面还有dsize和csize
+    pub offset: usize,
+    // block解压后在整个解压buf中的offset, 即前面所有block的dsize之和
+    pub de_offset: usize,
 }
 
 pub fn parse_record_blocks<'a>(
     data: &'a [u8],
-    header: &'a Header,
-) -> IResult<&'a [u8], Vec<RecordBlockSize>> {
-    match &header.version {
-        Version::V1 => parse_record_blocks_v1(data),
-        Version::V2 => parse_record_blocks_v2(data),
+    header: &Header,
+) -> ParseResult<'a, Vec<RecordBlockSize>> {
+    let (data, mut blocks) = match &header.version {
+        Version::V1 => parse_record_blocks_v1(data)?,
+        Version::V2 => parse_record_blocks_v2(data)?,
+        Version::V3 => parse_record_blocks_v3(data)?,
+    };
+    let mut offset = 0;
+    let mut de_offset = 0;
+    let size_error = || failure(MdictError::Malformed("record block size overflow".to_string()));
+    for block in blocks.iter_mut() {
+        // v1 v2 的block是连续存放的, v3 的offset在解析时已经得到
+        if !matches!(header.version, Version::V3) {
+            block.offset = offset;
+            offset = match offset.checked_add(block.csize) {
+                Some(offset) => offset,
+                None => return size_error(),
+            };
+        }
+        block.de_offset = de_offset;
+        de_offset = match de_offset.checked_add(block.dsize) {
+            Some(de_offset) => de_offset,
+            None => return size_error(),
+        };
     }
+    Ok((data, blocks))
 }
 
-fn parse_record_blocks_v1(data: &[u8]) -> IResult<&[u8], Vec<RecordBlockSize>> {
+fn parse_record_blocks_v1(data: &[u8]) -> ParseResult<'_, Vec<RecordBlockSize>> {
     let (data, (records_num, _entries_num, record_info_len, _record_buf_len)) =
         tuple((be_u32, be_u32, be_u32, be_u32))(data)?;
 
-    assert_eq!(records_num * 8, record_info_len);
+    if records_num as u64 * 8 != record_info_len as u64 {
+        return failure(MdictError::Malformed(
+            "record block info length mismatch".to_string(),
+        ));
+    }
 
     count(
         map(tuple((be_u32, be_u32)), |(csize, dsize)| RecordBlockSize {
             csize: csize as usize,
             dsize: dsize as usize,
+            offset: 0,
+            de_offset: 0,
         }),
         records_num as usize,
     )(data)
 }
 
-fn parse_record_blocks_v2(data: &[u8]) -> IResult<&[u8], Vec<RecordBlockSize>> {
+fn parse_record_blocks_v2(data: &[u8]) -> ParseResult<'_, Vec<RecordBlockSize>> {
     let (data, (records_num, _entries_num, record_info_len, _record_buf_len)) =
         tuple((be_u64, be_u64, be_u64, be_u64))(data)?;
 
-    assert_eq!(records_num * 16, record_info_len,);
+    if records_num.checked_mul(16) != Some(record_info_len) {
+        return failure(MdictError::Malformed(
+            "record block info length mismatch".to_string(),
+        ));
+    }
 
     count(
         map(tuple((be_u64, be_u64)), |(csize, dsize)| RecordBlockSize {
             csize: csize as usize,
             dsize: dsize as usize,
+            offset: 0,
+            de_offset: 0,
         }),
         records_num as usize,
     )(data)
 }
 
+/// v3 record data section: block number, total size, 然后是每个block的 dsize csize block
+/// 返回的offset是相对于data的, data就是record block buf
+fn parse_record_blocks_v3(data: &[u8]) -> ParseResult<'_, Vec<RecordBlockSize>> {
+    let (mut buf, (records_num, _total_size)) = tuple((be_u32, be_u64))(data)?;
+
+    let mut blocks = vec![];
+    for _ in 0..records_num {
+        let (remain, (dsize, csize)) = tuple((be_u32, be_u32))(buf)?;
+        let offset = data.len() - remain.len();
+        let (remain, _) = take(csize)(remain)?;
+        blocks.push(RecordBlockSize {
+            csize: csize as usize,
+            dsize: dsize as usize,
+            offset,
+            de_offset: 0,
+        });
+        buf = remain;
+    }
+    Ok((buf, blocks))
+}
+
+/// record_key: 有注册码时使用注册码得到的key解密, 否则使用checksum的ripemd128
 pub(crate) fn record_block_parser<'a>(
     size: usize,
     dsize: usize,
-) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], Vec<u8>> {
-    map(
-        tuple((le_u32, take(4_usize), take(size - 8))),
-        move |(enc, checksum, encrypted)| {
-            // 规范里面好像没有加密这步
-            let enc_method = (enc >> 4) & 0xf;
-            let comp_method = enc & 0xf;
-
-            let mut md = Ripemd128::new();
-            md.update(checksum);
-            let key = md.finalize();
-
-            let data: Vec<u8> = match enc_method {
-                0 => Vec::from(encrypted),
-                1 => fast_decrypt(encrypted, key.as_slice()),
-                2 => {
-                    let decrypt = vec![];
-                    decrypt
-                }
-                _ => panic!("unknown enc method: {}", enc_method),
-            };
+    record_key: Option<Vec<u8>>,
+) -> impl FnMut(&'a [u8]) -> ParseResult<'a, Vec<u8>> {
+    move |data: &'a [u8]| {
+        let data_size = block_data_size(size).map_err(nom::Err::Failure)?;
+        let (data, (enc, checksum, encrypted)) =
+            tuple((le_u32, take(4_usize), take(data_size)))(data)?;
+        let enc_method = (enc >> 4) & 0xf;
+        let enc_size = ((enc >> 8) & 0xff) as usize;
+        let comp_method = enc & 0xf;
 
-            let decompressed = match comp_method {
-                0 => data,
-                1 => {
-                    let lzo = minilzo_rs::LZO::init().unwrap();
-                    lzo.decompress(&data[..], dsize).unwrap()
-                }
-                2 => {
-                    let mut v = vec![];
-                    ZlibDecoder::new(&data[..]).read_to_end(&mut v).unwrap();
-                    v
-                }
-                _ => panic!("unknown compression method: {}", comp_method),
-            };
+        let key = block_key(checksum, record_key.as_deref());
+        let decompressed = decrypt_block(encrypted, enc_method, enc_size, &key)
+            .and_then(|data| decompress_block(data, comp_method, dsize))
+            .map_err(nom::Err::Failure)?;
 
-            decompressed
-        },
-    )
+        Ok((data, decompressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::encrypt_block;
+
+    use super::*;
+
+    #[test]
+    fn encrypted_record_blocks() {
+        let data = "record data, longer than the encrypted prefix\0".repeat(10).into_bytes();
+        let user_key = [7u8; 16];
+        for (enc_method, enc_size) in [(1, 16), (2, 16), (2, 255), (1, 0)] {
+            for record_key in [None, Some(&user_key[..])] {
+                let block = encrypt_block(&data, 2, enc_method, enc_size, record_key);
+                let (_, decompressed) =
+                    record_block_parser(block.len(), data.len(), record_key.map(Vec::from))(&block).unwrap();
+                assert_eq!(decompressed, data, "{} {} {:?}", enc_method, enc_size, record_key);
+            }
+        }
+        // 没有压缩时加密的部分就是数据本身
+        let block = encrypt_block(&data, 0, 2, 32, None);
+        assert_ne!(&block[8..40], &data[..32]);
+        assert_eq!(record_block_parser(block.len(), data.len(), None)(&block).unwrap().1, data);
+    }
+
+    #[test]
+    fn wrong_key_fails_to_decompress() {
+        let data = b"record data".repeat(10);
+        let block = encrypt_block(&data, 2, 2, 16, Some(&[7; 16]));
+        assert!(record_block_parser(block.len(), data.len(), Some(vec![8; 16]))(&block).is_err());
+    }
+
+    #[test]
+    fn block_smaller_than_its_header() {
+        let block = encrypt_block(b"record", 0, 0, 0, None);
+        for csize in 0..8 {
+            let result = record_block_parser(csize, 6, None)(&block);
+            assert!(matches!(result, Err(nom::Err::Failure(MdictError::Malformed(_)))), "{}", csize);
+        }
+        assert_eq!(record_block_parser(block.len(), 6, None)(&block).unwrap().1, b"record");
+    }
+
+    #[test]
+    fn oversized_dsize_is_rejected() {
+        let block = encrypt_block(&b"record".repeat(100), 2, 0, 0, None);
+        let csize = block.len();
+        // 压缩比超过1032:1的dsize, 和超过block大小上限的dsize
+        for dsize in [csize * 1032, usize::MAX] {
+            let result = record_block_parser(csize, dsize, None)(&block);
+            assert!(matches!(result, Err(nom::Err::Failure(MdictError::Malformed(_)))), "{}", dsize);
+        }
+    }
 }
@@ -0,0 +1,38 @@
+use std::fs::File;
+use std::ops::{Deref, Range};
+use std::path::Path;
+
+use memmap2::Mmap;
+
+use crate::mdict::error::MdictError;
+
+/// mdx或mdd文件中key block和record block所在的bytes
+/// new 只复制这一部分到内存, open 使用mmap, 由操作系统按需读取和换出, 大文件不会占用太多内存
+#[derive(Debug)]
+pub(crate) enum Source {
+    Memory(Vec<u8>),
+    Mmap(Mmap),
+}
+
+impl Deref for Source {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Source::Memory(data) => data,
+            Source::Mmap(mmap) => mmap,
+        }
+    }
+}
+
+pub(crate) fn map_file<P: AsRef<Path>>(path: P) -> Result<Mmap, MdictError> {
+    let file = File::open(path)?;
+    // 文件在Mdx/Mdd存在期间不能被修改或截断
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// part 在 data 中的位置, part 必须是 data 的一部分
+pub(crate) fn range_in(data: &[u8], part: &[u8]) -> Range<usize> {
+    let start = part.as_ptr() as usize - data.as_ptr() as usize;
+    start..start + part.len()
+}
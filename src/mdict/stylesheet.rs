@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+/// header中的 StyleSheet 属性, 每3行一组: 编号, 开始标记, 结束标记
+/// 释义中的 `1` 表示后面的文本使用1号样式, 直到下一个 `N` 或者释义结束
+#[derive(Debug, Clone)]
+pub struct StyleSheet {
+    styles: HashMap<String, (String, String)>,
+    marker: Regex,
+}
+
+impl StyleSheet {
+    pub fn parse(text: &str) -> StyleSheet {
+        let lines: Vec<&str> = text.lines().collect();
+        let styles = lines
+            .chunks(3)
+            .filter_map(|c| match c {
+                [id, begin, end] => Some((id.trim().to_string(), (begin.to_string(), end.to_string()))),
+                _ => None,
+            })
+            .collect();
+        StyleSheet {
+            styles,
+            marker: Regex::new(r"`(\d+)`").unwrap(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.styles.is_empty()
+    }
+
+    /// 把释义中的 `N` 替换成对应的开始和结束标记, 没有定义的编号原样保留
+    pub fn apply(&self, definition: &str) -> String {
+        if self.is_empty() {
+            return definition.to_string();
+        }
+        let mut styled = String::with_capacity(definition.len());
+        // 当前样式的结束标记, 在下一个 `N` 之前或者释义结尾补上
+        let mut end: Option<&str> = None;
+        let mut last = 0;
+        for cap in self.marker.captures_iter(definition) {
+            let m = cap.get(0).unwrap();
+            let Some((begin, e)) = self.styles.get(&cap[1]) else {
+                continue;
+            };
+            push_styled(&mut styled, &definition[last..m.start()], end);
+            styled.push_str(begin);
+            end = Some(e);
+            last = m.end();
+        }
+        push_styled(&mut styled, &definition[last..], end);
+        styled
+    }
+}
+
+/// 文本以换行结尾时, 结束标记放在换行之前
+fn push_styled(styled: &mut String, text: &str, end: Option<&str>) {
+    match end {
+        Some(end) if text.ends_with('\n') => {
+            styled.push_str(text.trim_end());
+            styled.push_str(end);
+            styled.push_str("\r\n");
+        }
+        Some(end) => {
+            styled.push_str(text);
+            styled.push_str(end);
+        }
+        None => styled.push_str(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stylesheet() -> StyleSheet {
+        StyleSheet::parse("1\n<b>\n</b>\n2\n<i>\n</i>\n")
+    }
+
+    #[test]
+    fn apply_markers() {
+        let stylesheet = stylesheet();
+        assert_eq!(stylesheet.apply("`1`apple`2`n. fruit"), "<b>apple</b><i>n. fruit</i>");
+        // 第一个标记之前的文本没有样式
+        assert_eq!(stylesheet.apply("see `1`apple"), "see <b>apple</b>");
+        assert_eq!(stylesheet.apply("no markers"), "no markers");
+    }
+
+    #[test]
+    fn undefined_marker_is_kept() {
+        let stylesheet = stylesheet();
+        assert_eq!(stylesheet.apply("`1`apple`9`pie"), "<b>apple`9`pie</b>");
+        assert_eq!(stylesheet.apply("`9`apple"), "`9`apple");
+    }
+
+    #[test]
+    fn end_tag_goes_before_trailing_newline() {
+        let stylesheet = stylesheet();
+        assert_eq!(stylesheet.apply("`1`apple  \n`2`fruit\n"), "<b>apple</b>\r\n<i>fruit</i>\r\n");
+        // 没有样式的文本不改动
+        assert_eq!(stylesheet.apply("apple  \n`1`fruit"), "apple  \n<b>fruit</b>");
+    }
+
+    #[test]
+    fn incomplete_group_is_ignored() {
+        let stylesheet = StyleSheet::parse("1\n<b>\n</b>\n2\n<i>");
+        assert_eq!(stylesheet.apply("`1`a`2`b"), "<b>a`2`b</b>");
+        let stylesheet = StyleSheet::parse("1\n<b>");
+        assert!(stylesheet.is_empty());
+        assert_eq!(stylesheet.apply("`1`a"), "`1`a");
+    }
+
+    #[test]
+    fn crlf_stylesheet() {
+        let stylesheet = StyleSheet::parse("1\r\n<b>\r\n</b>\r\n");
+        assert_eq!(stylesheet.apply("`1`a"), "<b>a</b>");
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt;
+
+use serde_derive::Serialize;
+
+/// Mdx::verify 的结果, 检查完所有block后才返回, 不会在第一个问题处停止
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub key_blocks: usize,
+    pub record_blocks: usize,
+    pub entries: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// 一个问题, part 是出问题的部分, e.g. "key block 3"
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub part: String,
+    pub message: String,
+}
+
+impl VerifyReport {
+    /// 没有发现任何问题
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub(crate) fn problem(&mut self, part: &str, message: impl Into<String>) {
+        self.problems.push(Problem {
+            part: part.to_string(),
+            message: message.into(),
+        });
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} key blocks, {} record blocks, {} entries",
+            self.key_blocks, self.record_blocks, self.entries
+        )?;
+        for p in &self.problems {
+            writeln!(f, "{}: {}", p.part, p.message)?;
+        }
+        if self.is_ok() {
+            write!(f, "ok")
+        } else {
+            write!(f, "{} problems", self.problems.len())
+        }
+    }
+}
@@ -0,0 +1,802 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use adler32::adler32;
+use encoding::all::UTF_16LE;
+use encoding::{EncoderTrap, EncodingRef};
+use ripemd::{Digest, Ripemd128};
+
+use crate::mdict::error::MdictError;
+use crate::mdict::header::{escape_entities, parse_header, Header};
+use crate::util::{compress_block, fast_encrypt, is_utf16, text_decoder};
+
+/// 默认的block大小, 是解压后的大小
+pub const DEFAULT_BLOCK_SIZE: usize = 64 * 1024;
+
+/// 默认的mdd分卷大小, 超过时写到 .1.mdd .2.mdd 中
+pub const DEFAULT_VOLUME_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// key block 和 record block 的压缩方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    None,
+    Lzo,
+    #[default]
+    Zlib,
+}
+
+impl Compression {
+    /// block开头的压缩类型
+    fn method(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Lzo => 1,
+            Compression::Zlib => 2,
+        }
+    }
+}
+
+/// 由排好序的 (key, definition) 生成 v2 的mdx文件
+///
+/// ```no_run
+/// use mdict_rs::mdict::{Compression, MdxWriter};
+///
+/// let entries = vec![("apple", "<b>apple</b> a fruit"), ("banana", "<b>banana</b> another fruit")];
+/// let mut file = std::fs::File::create("glossary.mdx")?;
+/// MdxWriter::new("Glossary")
+///     .compression(Compression::Lzo)
+///     .encrypt_key_info(true)
+///     .write(entries, &mut file)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct MdxWriter {
+    title: String,
+    description: String,
+    stylesheet: String,
+    encoding: String,
+    format: String,
+    creation_date: String,
+    key_case_sensitive: bool,
+    strip_key: bool,
+    blocks: BlockOptions,
+}
+
+impl MdxWriter {
+    pub fn new(title: &str) -> MdxWriter {
+        MdxWriter {
+            title: title.to_string(),
+            description: String::new(),
+            stylesheet: String::new(),
+            encoding: "UTF-8".to_string(),
+            format: "Html".to_string(),
+            creation_date: String::new(),
+            key_case_sensitive: false,
+            strip_key: true,
+            blocks: BlockOptions::default(),
+        }
+    }
+
+    pub fn description(mut self, description: &str) -> MdxWriter {
+        self.description = description.to_string();
+        self
+    }
+
+    /// 见 StyleSheet, 没有时为空
+    pub fn stylesheet(mut self, stylesheet: &str) -> MdxWriter {
+        self.stylesheet = stylesheet.to_string();
+        self
+    }
+
+    /// key 和 definition 的编码, 默认 "UTF-8"
+    pub fn encoding(mut self, encoding: &str) -> MdxWriter {
+        self.encoding = encoding.to_string();
+        self
+    }
+
+    /// "Html" 或 "Text", 默认 "Html"
+    pub fn format(mut self, format: &str) -> MdxWriter {
+        self.format = format.to_string();
+        self
+    }
+
+    /// e.g. "2024-01-01"
+    pub fn creation_date(mut self, creation_date: &str) -> MdxWriter {
+        self.creation_date = creation_date.to_string();
+        self
+    }
+
+    /// KeyCaseSensitive, 默认 false
+    pub fn key_case_sensitive(mut self, key_case_sensitive: bool) -> MdxWriter {
+        self.key_case_sensitive = key_case_sensitive;
+        self
+    }
+
+    /// StripKey, 默认 true
+    pub fn strip_key(mut self, strip_key: bool) -> MdxWriter {
+        self.strip_key = strip_key;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> MdxWriter {
+        self.blocks.compression = compression;
+        self
+    }
+
+    /// 每个block解压后的大小, 一个entry不会被分到两个block中, 默认 DEFAULT_BLOCK_SIZE
+    pub fn block_size(mut self, block_size: usize) -> MdxWriter {
+        self.blocks.block_size = block_size.max(1);
+        self
+    }
+
+    /// Encrypted="2": 加密 key block info, 读取时不需要注册码
+    pub fn encrypt_key_info(mut self, encrypt_key_info: bool) -> MdxWriter {
+        self.blocks.encrypt_key_info = encrypt_key_info;
+        self
+    }
+
+    /// entries 必须按 Header::normalize_key 排好序, 同一个key可以有多个definition
+    pub fn write<I, K, D, W>(&self, entries: I, mut out: W) -> Result<(), MdictError>
+    where
+        I: IntoIterator<Item=(K, D)>,
+        K: AsRef<str>,
+        D: AsRef<str>,
+        W: Write,
+    {
+        let header_text = format!(
+            "<Dictionary GeneratedByEngineVersion=\"2.0\" RequiredEngineVersion=\"2.0\" Encrypted=\"{}\" \
+             Encoding=\"{}\" Format=\"{}\" Stripkey=\"{}\" CreationDate=\"{}\" Compact=\"No\" Compat=\"No\" \
+             KeyCaseSensitive=\"{}\" Description=\"{}\" Title=\"{}\" DataSourceFormat=\"106\" \
+             StyleSheet=\"{}\" Left2Right=\"Yes\" RegisterBy=\"\"/>\r\n\0",
+            if self.blocks.encrypt_key_info { 2 } else { 0 },
+            escape_entities(&self.encoding),
+            escape_entities(&self.format),
+            yes_no(self.strip_key),
+            escape_entities(&self.creation_date),
+            yes_no(self.key_case_sensitive),
+            escape_entities(&self.description),
+            escape_entities(&self.title),
+            escape_entities(&self.stylesheet),
+        );
+        let (header_buf, header) = header_section(&header_text)?;
+
+        let encoder = text_decoder(&header.encoding)?;
+        let terminator: &[u8] = if is_utf16(&header.encoding) { b"\x00\x00" } else { b"\x00" };
+        let mut keys = vec![];
+        let mut records = vec![];
+        let mut sorted = SortedKeys::new(&header);
+        for (key, definition) in entries {
+            let key = key.as_ref();
+            sorted.push(key)?;
+            let mut record = encode(encoder, definition.as_ref())?;
+            record.extend_from_slice(terminator);
+            keys.push(Key {
+                key: encode(encoder, key)?,
+                size: record.len(),
+            });
+            records.push(record);
+        }
+
+        out.write_all(&self.blocks.write_to_vec(header_buf, &keys, records, terminator)?)?;
+        Ok(())
+    }
+}
+
+/// 由资源文件生成mdd文件, key是以`\`分隔的资源路径, e.g. `\img\a.png`
+///
+/// ```no_run
+/// use mdict_rs::mdict::MddWriter;
+///
+/// // 写入 glossary.mdd, 太大时还有 glossary.1.mdd glossary.2.mdd ...
+/// let volumes = MddWriter::new("Glossary").write_dir("./resources", "glossary.mdd")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct MddWriter {
+    title: String,
+    description: String,
+    volume_size: u64,
+    blocks: BlockOptions,
+}
+
+impl MddWriter {
+    pub fn new(title: &str) -> MddWriter {
+        MddWriter {
+            title: title.to_string(),
+            description: String::new(),
+            volume_size: DEFAULT_VOLUME_SIZE,
+            blocks: BlockOptions::default(),
+        }
+    }
+
+    pub fn description(mut self, description: &str) -> MddWriter {
+        self.description = description.to_string();
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> MddWriter {
+        self.blocks.compression = compression;
+        self
+    }
+
+    /// 每个block解压后的大小, 一个资源不会被分到两个block中, 默认 DEFAULT_BLOCK_SIZE
+    pub fn block_size(mut self, block_size: usize) -> MddWriter {
+        self.blocks.block_size = block_size.max(1);
+        self
+    }
+
+    /// write_dir 时每个分卷中资源的总大小, 一个资源不会被分到两个分卷中, 默认 DEFAULT_VOLUME_SIZE
+    pub fn volume_size(mut self, volume_size: u64) -> MddWriter {
+        self.volume_size = volume_size.max(1);
+        self
+    }
+
+    /// Encrypted="2": 加密 key block info, 读取时不需要注册码
+    pub fn encrypt_key_info(mut self, encrypt_key_info: bool) -> MddWriter {
+        self.blocks.encrypt_key_info = encrypt_key_info;
+        self
+    }
+
+    /// 写一个mdd文件, entries 是 (资源路径, bytes), 必须按 Header::normalize_key 排好序, 即不区分大小写
+    /// 资源路径中的`/`会转换成`\`, e.g. "img/a.png" -> "\img\a.png"
+    pub fn write<I, K, D, W>(&self, entries: I, mut out: W) -> Result<(), MdictError>
+    where
+        I: IntoIterator<Item=(K, D)>,
+        K: AsRef<str>,
+        D: Into<Vec<u8>>,
+        W: Write,
+    {
+        let (header_buf, header) = self.header()?;
+        let mut keys = vec![];
+        let mut records = vec![];
+        let mut sorted = SortedKeys::new(&header);
+        for (key, data) in entries {
+            let key = resource_key(key.as_ref());
+            sorted.push(&key)?;
+            let record = data.into();
+            keys.push(Key {
+                key: encode(UTF_16LE, &key)?,
+                size: record.len(),
+            });
+            records.push(record);
+        }
+
+        out.write_all(&self.blocks.write_to_vec(header_buf, &keys, records, MDD_TERMINATOR)?)?;
+        Ok(())
+    }
+
+    /// mdd的header, 和读取时使用的 Header
+    fn header(&self) -> Result<(Vec<u8>, Header), MdictError> {
+        // mdd的key总是utf-16le, 按小写排序, 不去掉标点
+        let header_text = format!(
+            "<Library_Data GeneratedByEngineVersion=\"2.0\" RequiredEngineVersion=\"2.0\" Encrypted=\"{}\" \
+             Encoding=\"\" Format=\"\" Stripkey=\"No\" CreationDate=\"\" Compact=\"No\" Compat=\"No\" \
+             KeyCaseSensitive=\"No\" Description=\"{}\" Title=\"{}\" DataSourceFormat=\"106\" \
+             StyleSheet=\"\" RegisterBy=\"\"/>\r\n\0",
+            if self.blocks.encrypt_key_info { 2 } else { 0 },
+            escape_entities(&self.description),
+            escape_entities(&self.title),
+        );
+        header_section(&header_text)
+    }
+
+    /// 把目录下的所有文件写成mdd, 资源路径是相对于dir的路径
+    /// 超过 volume_size 时分卷写到 mdd.1.mdd mdd.2.mdd ..., 返回写好的所有文件
+    pub fn write_dir<P: AsRef<Path>, Q: AsRef<Path>>(&self, dir: P, mdd: Q) -> Result<Vec<PathBuf>, MdictError> {
+        let dir = dir.as_ref();
+        let mut files = vec![];
+        walk_dir(dir, &mut files)?;
+
+        let mut resources = vec![];
+        for path in files {
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            let components = relative
+                .components()
+                .map(|c| {
+                    c.as_os_str()
+                        .to_str()
+                        .map(String::from)
+                        .ok_or_else(|| MdictError::BadEncoding(format!("{:?}", relative)))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let key = format!("\\{}", components.join("\\"));
+            let size = fs::metadata(&path)?.len();
+            resources.push((key, path, size));
+        }
+        resources.sort_by(|a, b| a.0.to_lowercase().cmp(&b.0.to_lowercase()).then_with(|| a.0.cmp(&b.0)));
+
+        // 按资源大小分卷, 每个分卷至少有一个资源
+        let mut volumes: Vec<Vec<(String, PathBuf, u64)>> = vec![vec![]];
+        let mut volume_size = 0;
+        for resource in resources {
+            let volume = volumes.last_mut().unwrap();
+            if !volume.is_empty() && volume_size + resource.2 > self.volume_size {
+                volumes.push(vec![]);
+                volume_size = 0;
+            }
+            volume_size += resource.2;
+            volumes.last_mut().unwrap().push(resource);
+        }
+
+        let mut written = vec![];
+        for (i, volume) in volumes.iter().enumerate() {
+            let path = volume_path(mdd.as_ref(), i);
+            self.write_volume(volume, &path)?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// 一次只读取一个资源文件, 压缩好一个block就写入文件, 不需要把整个分卷放在内存中
+    fn write_volume(&self, resources: &[(String, PathBuf, u64)], path: &Path) -> Result<(), MdictError> {
+        let (header_buf, header) = self.header()?;
+        let mut keys = vec![];
+        let mut sorted = SortedKeys::new(&header);
+        for (key, file, size) in resources {
+            sorted.push(key)?;
+            keys.push(Key {
+                key: encode(UTF_16LE, key)?,
+                size: usize::try_from(*size)
+                    .map_err(|_| MdictError::Malformed(format!("{:?} is too large: {} bytes", file, size)))?,
+            });
+        }
+
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&header_buf)?;
+        let records = resources.iter().map(|(_, file, _)| fs::read(file));
+        self.blocks.write(&keys, records, MDD_TERMINATOR, &mut out)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// mdd的key是utf-16le, 结尾是两个字节
+const MDD_TERMINATOR: &[u8] = b"\x00\x00";
+
+/// 第n个分卷的文件名, 0是mdd本身, e.g. "a.mdd" -> "a.1.mdd"
+pub fn volume_path(mdd: &Path, n: usize) -> PathBuf {
+    if n == 0 {
+        mdd.to_path_buf()
+    } else {
+        mdd.with_extension(format!("{}.mdd", n))
+    }
+}
+
+fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), MdictError> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk_dir(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// 资源路径转换成mdd中的key: 使用`\`分隔并以`\`开头, 保留大小写
+fn resource_key(path: &str) -> String {
+    let key = path.replace('/', "\\");
+    if key.starts_with('\\') {
+        key
+    } else {
+        format!("\\{}", key)
+    }
+}
+
+/// 写入时的一个entry, key是编码后的bytes, 不包含结尾的 \x00, size是record的长度
+/// 写key block时只需要record的长度, record在写record block时才读取
+struct Key {
+    key: Vec<u8>,
+    size: usize,
+}
+
+#[derive(Debug, Clone)]
+struct BlockOptions {
+    compression: Compression,
+    block_size: usize,
+    encrypt_key_info: bool,
+}
+
+impl Default for BlockOptions {
+    fn default() -> BlockOptions {
+        BlockOptions {
+            compression: Compression::default(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            encrypt_key_info: false,
+        }
+    }
+}
+
+/// 一个写好的key block和它在key block info中的信息
+struct Block {
+    data: Vec<u8>,
+    dsize: usize,
+    entries_num: usize,
+    first_key: Vec<u8>,
+    last_key: Vec<u8>,
+}
+
+impl BlockOptions {
+    /// header之后的部分: key block header, key block info, key blocks, record block header, record block info, record blocks
+    /// terminator: key 结尾的 \x00, utf-16 是 \x00\x00
+    /// records: 按顺序的每个record, 长度必须和keys中的size相同, 压缩好一个record block就写入out
+    /// record block info 在record blocks之前, 先写入占位, 写完所有record block之后再回来填上
+    fn write<I, R, E, W>(&self, keys: &[Key], records: I, terminator: &[u8], out: &mut W) -> Result<(), MdictError>
+    where
+        I: IntoIterator<Item=Result<R, E>>,
+        R: AsRef<[u8]>,
+        MdictError: From<E>,
+        W: Write + Seek,
+    {
+        out.write_all(&self.key_section(keys, terminator)?)?;
+
+        // record block的划分只取决于record的长度, 所以可以先知道block的个数
+        let mut blocks_num = 0;
+        let mut dsize = 0;
+        for (i, key) in keys.iter().enumerate() {
+            dsize += key.size;
+            if dsize >= self.block_size || i + 1 == keys.len() {
+                blocks_num += 1;
+                dsize = 0;
+            }
+        }
+        let section_start = out.stream_position()?;
+        out.write_all(&vec![0; 32 + blocks_num * 16])?;
+
+        let mut info = vec![];
+        let mut record_blocks_len = 0;
+        let mut record_buf = vec![];
+        let mut records = records.into_iter();
+        for (i, key) in keys.iter().enumerate() {
+            let record = records
+                .next()
+                .ok_or_else(|| MdictError::Malformed(format!("{} records for {} keys", i, keys.len())))??;
+            let record = record.as_ref();
+            // e.g. 资源文件在写入期间被修改
+            if record.len() != key.size {
+                return Err(MdictError::Malformed(format!(
+                    "record {} is {} bytes, expected {}",
+                    i,
+                    record.len(),
+                    key.size
+                )));
+            }
+            record_buf.extend_from_slice(record);
+            if record_buf.len() >= self.block_size || i + 1 == keys.len() {
+                let block = self.block(&record_buf)?;
+                out.write_all(&block)?;
+                info.extend_from_slice(&(block.len() as u64).to_be_bytes());
+                info.extend_from_slice(&(record_buf.len() as u64).to_be_bytes());
+                record_blocks_len += block.len();
+                record_buf.clear();
+            }
+        }
+
+        let end = out.stream_position()?;
+        out.seek(SeekFrom::Start(section_start))?;
+        for n in [blocks_num, keys.len(), blocks_num * 16, record_blocks_len] {
+            out.write_all(&(n as u64).to_be_bytes())?;
+        }
+        out.write_all(&info)?;
+        out.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    /// 在内存中写出整个文件, header 之后是 write 写出的部分
+    fn write_to_vec(
+        &self,
+        header: Vec<u8>,
+        keys: &[Key],
+        records: Vec<Vec<u8>>,
+        terminator: &[u8],
+    ) -> Result<Vec<u8>, MdictError> {
+        let mut out = Cursor::new(header);
+        out.seek(SeekFrom::End(0))?;
+        self.write(keys, records.into_iter().map(Ok::<_, MdictError>), terminator, &mut out)?;
+        Ok(out.into_inner())
+    }
+
+    /// key block header, key block info, key blocks
+    fn key_section(&self, keys: &[Key], terminator: &[u8]) -> Result<Vec<u8>, MdictError> {
+        let mut key_blocks = vec![];
+        let mut key_buf = vec![];
+        let mut first = 0;
+        let mut record_offset = 0;
+        for (i, key) in keys.iter().enumerate() {
+            key_buf.extend_from_slice(&(record_offset as u64).to_be_bytes());
+            key_buf.extend_from_slice(&key.key);
+            key_buf.extend_from_slice(terminator);
+            record_offset += key.size;
+
+            if key_buf.len() >= self.block_size || i + 1 == keys.len() {
+                key_blocks.push(Block {
+                    data: self.block(&key_buf)?,
+                    dsize: key_buf.len(),
+                    entries_num: i + 1 - first,
+                    first_key: keys[first].key.clone(),
+                    last_key: key.key.clone(),
+                });
+                key_buf.clear();
+                first = i + 1;
+            }
+        }
+
+        let mut out = vec![];
+
+        // key block info 中的 first/last key: u16 长度(utf-16是code unit的个数) + text + 结尾
+        let mut info = vec![];
+        for block in &key_blocks {
+            info.extend_from_slice(&(block.entries_num as u64).to_be_bytes());
+            for key in [&block.first_key, &block.last_key] {
+                let len = key.len() / terminator.len();
+                let len = u16::try_from(len)
+                    .map_err(|_| MdictError::Malformed(format!("key is too long: {} bytes", key.len())))?;
+                info.extend_from_slice(&len.to_be_bytes());
+                info.extend_from_slice(key);
+                info.extend_from_slice(terminator);
+            }
+            info.extend_from_slice(&(block.data.len() as u64).to_be_bytes());
+            info.extend_from_slice(&(block.dsize as u64).to_be_bytes());
+        }
+        let info_block = self.key_block_info(&info)?;
+        let key_blocks_len: usize = key_blocks.iter().map(|b| b.data.len()).sum();
+
+        let mut key_block_header = vec![];
+        for n in [key_blocks.len(), keys.len(), info.len(), info_block.len(), key_blocks_len] {
+            key_block_header.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+        out.extend_from_slice(&key_block_header);
+        out.extend_from_slice(&adler32(&key_block_header[..]).unwrap().to_be_bytes());
+        out.extend_from_slice(&info_block);
+        for block in &key_blocks {
+            out.extend_from_slice(&block.data);
+        }
+        Ok(out)
+    }
+
+    /// 压缩类型, 解压后数据的adler32, 压缩后的数据
+    fn block(&self, data: &[u8]) -> Result<Vec<u8>, MdictError> {
+        let method = self.compression.method();
+        let mut block = method.to_le_bytes().to_vec();
+        block.extend_from_slice(&adler32(data).unwrap().to_be_bytes());
+        block.extend_from_slice(&compress_block(data, method)?);
+        Ok(block)
+    }
+
+    /// key block info 总是zlib压缩, 加密时使用checksum的ripemd128作为key
+    fn key_block_info(&self, info: &[u8]) -> Result<Vec<u8>, MdictError> {
+        let mut block = 2_u32.to_le_bytes().to_vec();
+        let checksum = adler32(info).unwrap().to_be_bytes();
+        block.extend_from_slice(&checksum);
+        let compressed = compress_block(info, 2)?;
+        if self.encrypt_key_info {
+            let mut md = Ripemd128::new();
+            md.update(checksum);
+            md.update(0x3695_u32.to_le_bytes());
+            block.extend(fast_encrypt(&compressed, md.finalize().as_slice()));
+        } else {
+            block.extend(compressed);
+        }
+        Ok(block)
+    }
+}
+
+/// 检查key是否按 Header::normalize_key 排序, 查词时的二分查找依赖这个顺序
+struct SortedKeys<'a> {
+    header: &'a Header,
+    prev: Option<(String, String)>,
+}
+
+impl<'a> SortedKeys<'a> {
+    fn new(header: &'a Header) -> SortedKeys<'a> {
+        SortedKeys { header, prev: None }
+    }
+
+    fn push(&mut self, key: &str) -> Result<(), MdictError> {
+        let normalized = self.header.normalize_key(key);
+        if let Some((prev, prev_normalized)) = &self.prev {
+            if normalized < *prev_normalized {
+                return Err(MdictError::UnsortedKeys {
+                    prev: prev.clone(),
+                    key: key.to_string(),
+                });
+            }
+        }
+        self.prev = Some((key.to_string(), normalized));
+        Ok(())
+    }
+}
+
+/// header: u32 长度 + utf-16le 的header text + adler32
+/// 同时用reader解析一遍, 得到读取时使用的 Header
+fn header_section(text: &str) -> Result<(Vec<u8>, Header), MdictError> {
+    let text = encode(UTF_16LE, text)?;
+    let mut buf = (text.len() as u32).to_be_bytes().to_vec();
+    buf.extend_from_slice(&text);
+    buf.extend_from_slice(&adler32(&text[..]).unwrap().to_le_bytes());
+    let (_, header) = parse_header(&buf)?;
+    Ok((buf, header))
+}
+
+fn encode(encoder: EncodingRef, text: &str) -> Result<Vec<u8>, MdictError> {
+    encoder
+        .encode(text, EncoderTrap::Strict)
+        .map_err(|e| MdictError::BadEncoding(e.to_string()))
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value {
+        "Yes"
+    } else {
+        "No"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mdict::{Mdd, Mdx};
+
+    use super::*;
+
+    /// 按 normalize_key 排好序, "apple" 有两条释义
+    fn entries() -> Vec<(String, String)> {
+        let mut entries = vec![
+            ("apple".to_string(), "苹果 1".to_string()),
+            ("apple".to_string(), "苹果 2".to_string()),
+            ("Banana".to_string(), "<b>香蕉</b>".to_string()),
+            ("cherry".to_string(), String::new()),
+            ("中文".to_string(), "汉语".to_string()),
+        ];
+        entries.extend((0..40).map(|i| (format!("w{:02}", i), format!("释义 {}", i).repeat(i % 5 + 1))));
+        entries.sort_by_key(|(key, _)| key.to_lowercase());
+        entries
+    }
+
+    #[test]
+    fn mdx_read_back() {
+        let entries = entries();
+        for compression in [Compression::None, Compression::Lzo, Compression::Zlib] {
+            for block_size in [1, 37, 4096] {
+                for encrypt_key_info in [false, true] {
+                    for encoding in ["UTF-8", "UTF-16LE", "GBK"] {
+                        let case = format!("{:?} {} {} {}", compression, block_size, encrypt_key_info, encoding);
+                        let mut data = vec![];
+                        MdxWriter::new("read back")
+                            .compression(compression)
+                            .block_size(block_size)
+                            .encrypt_key_info(encrypt_key_info)
+                            .encoding(encoding)
+                            .write(entries.clone(), &mut data)
+                            .unwrap();
+                        let mdx = Mdx::new(&data).unwrap();
+
+                        let items: Vec<(String, String)> = mdx
+                            .items()
+                            .map(|r| r.map(|r| (r.text().to_string(), r.into_definition())))
+                            .collect::<Result<_, _>>()
+                            .unwrap();
+                        assert_eq!(items, entries, "{}", case);
+                        assert_eq!(mdx.lookup("APPLE").unwrap(), vec!["苹果 1", "苹果 2"], "{}", case);
+                        assert_eq!(mdx.lookup("中文").unwrap(), vec!["汉语"], "{}", case);
+                        assert_eq!(mdx.lookup("cherry").unwrap(), vec![""], "{}", case);
+                        assert!(mdx.lookup("durian").unwrap().is_empty(), "{}", case);
+                        let report = mdx.verify();
+                        assert!(report.is_ok(), "{}: {}", case, report);
+                        assert_eq!(report.entries, entries.len(), "{}", case);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mdd_read_back() {
+        let mut data = vec![];
+        MddWriter::new("read back")
+            .block_size(8)
+            .write([("a.css", b"body{}".to_vec()), ("img/B.png", b"PNG".to_vec())], &mut data)
+            .unwrap();
+        let mdd = Mdd::new(&data).unwrap();
+        assert_eq!(mdd.get("/a.css").unwrap(), Some(b"body{}".to_vec()));
+        assert_eq!(mdd.get("\\img\\B.png").unwrap(), Some(b"PNG".to_vec()));
+        assert_eq!(mdd.get("c.css").unwrap(), None);
+
+        let path = std::env::temp_dir().join(format!("mdict-read-back-{}.mdd", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let mdd = Mdd::open(&path);
+        fs::remove_file(&path).unwrap();
+        let mdd = mdd.unwrap();
+        assert_eq!(mdd.get("img/b.png").unwrap(), Some(b"PNG".to_vec()));
+        assert_eq!(mdd.entries().count(), 2);
+    }
+
+    #[test]
+    fn unsorted_keys_are_rejected() {
+        let result = MdxWriter::new("unsorted").write([("b", "1"), ("A", "2")], vec![]);
+        assert!(matches!(result, Err(MdictError::UnsortedKeys { prev, key }) if prev == "b" && key == "A"));
+        // 按 normalize_key 比较, 大小写和标点不影响顺序
+        assert!(MdxWriter::new("sorted").write([("A-b", "1"), ("ab", "2"), ("AC", "3")], vec![]).is_ok());
+    }
+
+    #[test]
+    fn key_longer_than_u16_is_rejected() {
+        // key block info 中key的长度是u16
+        let key = "k".repeat(u16::MAX as usize + 1);
+        let result = MdxWriter::new("long").write([(key.as_str(), "1")], vec![]);
+        assert!(matches!(result, Err(MdictError::Malformed(_))));
+
+        let key = "k".repeat(u16::MAX as usize);
+        let mut data = vec![];
+        MdxWriter::new("long").write([(key.as_str(), "1")], &mut data).unwrap();
+        assert_eq!(Mdx::new(&data).unwrap().lookup(&key).unwrap(), vec!["1"]);
+    }
+
+    /// 测试用的资源目录, 每个测试使用不同的name
+    fn resource_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mdict-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("img/icons")).unwrap();
+        fs::create_dir_all(dir.join("Sound")).unwrap();
+        fs::write(dir.join("style.css"), "body{}").unwrap();
+        fs::write(dir.join("a.css"), "a{}").unwrap();
+        fs::write(dir.join("img/B.png"), vec![1; 300]).unwrap();
+        fs::write(dir.join("img/icons/c.png"), vec![2; 300]).unwrap();
+        fs::write(dir.join("Sound/apple.mp3"), vec![3; 500]).unwrap();
+        dir
+    }
+
+    #[test]
+    fn write_dir_walks_and_sorts() {
+        let dir = resource_dir("write-dir");
+        let mdd = dir.with_extension("mdd");
+        let written = MddWriter::new("dir").block_size(256).write_dir(&dir, &mdd).unwrap();
+        assert_eq!(written, vec![mdd.clone()]);
+
+        let volume = Mdd::open(&mdd).unwrap();
+        let keys: Vec<&str> = volume.entries().map(|rs| rs.text()).collect();
+        // 子目录中的文件也写入, 不区分大小写排序
+        assert_eq!(keys, vec!["\\a.css", "\\img\\B.png", "\\img\\icons\\c.png", "\\Sound\\apple.mp3", "\\style.css"]);
+        assert_eq!(volume.get("img/icons/c.png").unwrap(), Some(vec![2; 300]));
+        assert_eq!(volume.get("sound/apple.mp3").unwrap(), Some(vec![3; 500]));
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&mdd).unwrap();
+    }
+
+    #[test]
+    fn write_dir_splits_volumes() {
+        let dir = resource_dir("write-volumes");
+        let mdd = dir.with_extension("mdd");
+        // a.css + img/B.png 在第一个分卷, c.png 和 apple.mp3 各自超过剩下的大小
+        let written = MddWriter::new("volumes").volume_size(600).write_dir(&dir, &mdd).unwrap();
+        assert_eq!(written, (0..3).map(|n| volume_path(&mdd, n)).collect::<Vec<_>>());
+        assert_eq!(written[1], dir.with_extension("1.mdd"));
+
+        let volumes: Vec<Mdd> = written.iter().map(|path| Mdd::open(path).unwrap()).collect();
+        let keys: Vec<Vec<&str>> = volumes.iter().map(|v| v.entries().map(|rs| rs.text()).collect()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                vec!["\\a.css", "\\img\\B.png"],
+                vec!["\\img\\icons\\c.png"],
+                vec!["\\Sound\\apple.mp3", "\\style.css"],
+            ]
+        );
+        assert_eq!(volumes[2].get("style.css").unwrap(), Some(b"body{}".to_vec()));
+        for path in written {
+            fs::remove_file(path).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changed_record_size_is_rejected() {
+        let options = BlockOptions::default();
+        let keys = [Key { key: b"a".to_vec(), size: 3 }];
+        let result = options.write(&keys, [Ok::<_, MdictError>(b"ab")], b"\x00", &mut Cursor::new(vec![]));
+        assert!(matches!(result, Err(MdictError::Malformed(_))));
+        let result = options.write(&keys, Vec::<Result<Vec<u8>, MdictError>>::new(), b"\x00", &mut Cursor::new(vec![]));
+        assert!(matches!(result, Err(MdictError::Malformed(_))));
+    }
+}
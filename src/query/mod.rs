@@ -1,24 +1,101 @@
+use std::convert::Infallible;
+
 use log::info;
+use mdict_rs::mdict::{fold_key, resolve_links};
+use mdict_rs::mdict::{Header, MdictError, Mdx, Resolved};
 use rusqlite::{Connection, named_params};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::registry::Registry;
+use crate::resource::rewrite_resource_links;
+
+/// 同一个词的多条释义之间的分隔
+const HOMOGRAPH_SEPARATOR: &str = "\n<hr/>\n";
 
-use crate::config::MDX_FILES;
-
-pub fn query(word: String) -> String {
-    let w = word;
-    for file in MDX_FILES {
-        let db_file = format!("{}{}", &file.to_string(), ".db");
-        let conn = Connection::open(&db_file).unwrap();
-        let mut stmt = conn
-            .prepare("select * from MDX_INDEX WHERE text= :word limit 1;")
-            .unwrap();
-        info!("query params={}", &w);
-
-        let mut rows = stmt.query(named_params! { ":word": w }).unwrap();
-        let row = rows.next().unwrap();
-        if let Some(row) = row {
-            let def = row.get::<usize, String>(1).unwrap();
-            return def;
+/// 按词典的顺序查询, 返回第一个找到的词典中的所有释义
+/// 有indexing生成的sqlite时查sqlite, 否则直接查mdx
+/// 释义是 @@@LINK= 时在同一个词典中跟随跳转
+pub fn query(word: String, registry: &Registry) -> String {
+    // 输入法可能输入分解形式的变音符号
+    let w: String = word.nfc().collect();
+    info!("query params={}", &w);
+    for dict in registry.dicts() {
+        let resolved = match &dict.db {
+            Some(db) => {
+                let conn = db.lock().unwrap();
+                let header = dict.mdx.header();
+                resolve_links(&w, |w| Ok::<_, Infallible>(query_db(&conn, header, w))).unwrap()
+            }
+            None => resolve_links(&w, |w| query_mdx(&dict.mdx, w)).unwrap_or_default(),
+        };
+        if !resolved.definitions.is_empty() {
+            return render(&dict.name, &resolved);
         }
     }
     "not found".to_string()
 }
+
+/// 查到的词典和跟随的跳转以注释的形式放在最前面
+/// 释义中的图片, css, 音频等资源改成从这个词典的mdd中读取, 见 rewrite_resource_links
+fn render(dict: &str, resolved: &Resolved) -> String {
+    let mut html = format!("<!-- dict {} -->\n", dict);
+    for r in &resolved.redirects {
+        html.push_str(&format!("<!-- @@@LINK {} -> {} -->\n", r.from, r.to));
+    }
+    html.push_str(&rewrite_resource_links(&resolved.definitions.join(HOMOGRAPH_SEPARATOR), dict));
+    html
+}
+
+/// 同一个词的所有释义, 按文件中的顺序
+/// 先按词典的 KeyCaseSensitive 和 StripKey 精确匹配, 见 Header::normalize_key
+/// 匹配不到时再全角转半角并去掉变音符号匹配, 见 fold_key
+fn query_db(conn: &Connection, header: &Header, word: &str) -> Vec<String> {
+    let defs = query_column(conn, "key", &header.normalize_key(word));
+    if !defs.is_empty() {
+        return defs;
+    }
+    query_column(conn, "folded", &header.normalize_key(&fold_key(word)))
+}
+
+/// 没有sqlite时直接查mdx, 和 query_db 一样精确匹配不到时再用 fold_key 匹配
+/// mdx中只能按key二分查找, 只有key本身是fold之后的形式时才能找到 e.g. "café" 可以查到 "cafe"
+fn query_mdx(mdx: &Mdx, word: &str) -> Result<Vec<String>, MdictError> {
+    let defs = mdx.lookup(word)?;
+    let folded = fold_key(word);
+    if !defs.is_empty() || folded == word {
+        return Ok(defs);
+    }
+    mdx.lookup(&folded)
+}
+
+fn query_column(conn: &Connection, column: &str, key: &str) -> Vec<String> {
+    let mut stmt = conn
+        .prepare_cached(&format!(
+            "select def from MDX_INDEX WHERE {}= :key order by rowid;",
+            column
+        ))
+        .unwrap();
+
+    stmt.query_map(named_params! { ":key": key }, |row| row.get::<usize, String>(0))
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use mdict_rs::mdict::MdxWriter;
+
+    use super::*;
+
+    #[test]
+    fn query_mdx_falls_back_to_folded_key() {
+        let mut data = vec![];
+        MdxWriter::new("fold").write([("cafe", "x"), ("naive", "y")], &mut data).unwrap();
+        let mdx = Mdx::new(&data).unwrap();
+        assert_eq!(query_mdx(&mdx, "cafe").unwrap(), vec!["x"]);
+        assert_eq!(query_mdx(&mdx, "café").unwrap(), vec!["x"]);
+        assert_eq!(query_mdx(&mdx, "ＮＡÏＶＥ").unwrap(), vec!["y"]);
+        assert!(query_mdx(&mdx, "tea").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log::{error, info};
+use rusqlite::Connection;
+
+use crate::config::{dict_name, mdd_paths, passcode};
+use mdict_rs::mdict::{Mdd, MdictError, Mdx};
+
+/// 一个加载好的词典: mdx, 同名的mdd资源文件, 以及indexing生成的sqlite
+pub struct Dictionary {
+    pub name: String,
+    pub mdx: Mdx,
+    // mdd和它的分卷, 没有mdd时为空
+    pub mdd: Vec<Mdd>,
+    // sqlite connection 不能在线程间共享, 查询时加锁
+    pub db: Option<Mutex<Connection>>,
+}
+
+/// 所有加载好的词典, 在main中创建一次, 通过 web::Data 在actix的worker之间共享
+pub struct Registry {
+    dicts: Vec<Dictionary>,
+}
+
+impl Dictionary {
+    /// 在mdd的所有分卷中查找资源
+    pub fn resource(&self, path: &str) -> Result<Option<Vec<u8>>, MdictError> {
+        for mdd in &self.mdd {
+            if let Some(bytes) = mdd.get(path)? {
+                return Ok(Some(bytes));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Registry {
+    /// 按MDX_FILES的顺序加载词典, 一个词典加载失败不影响其他词典
+    pub fn load(files: &[&str]) -> Registry {
+        Registry::new(files.iter().filter_map(|file| load_dictionary(file)).collect())
+    }
+
+    /// 查询时按dicts的顺序
+    pub fn new(dicts: Vec<Dictionary>) -> Registry {
+        Registry { dicts }
+    }
+
+    pub fn dicts(&self) -> impl Iterator<Item=&Dictionary> {
+        self.dicts.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Dictionary> {
+        self.dicts.iter().find(|d| d.name == name)
+    }
+}
+
+fn load_dictionary(file: &str) -> Option<Dictionary> {
+    let passcode = passcode(file);
+    let mdx = match Mdx::open_with_passcode(file, passcode.as_ref()) {
+        Ok(mdx) => mdx,
+        Err(e) => {
+            error!("load mdx file {} failed: {:?}", file, e);
+            return None;
+        }
+    };
+    info!("mdx file:{} loaded", file);
+
+    // 没有mdd的词典跳过, 一个分卷加载失败不影响其他分卷
+    let mut mdd = vec![];
+    for path in mdd_paths(file) {
+        match Mdd::open_with_passcode(&path, passcode.as_ref()) {
+            Ok(volume) => {
+                info!("mdd file:{:?} loaded", &path);
+                mdd.push(volume);
+            }
+            Err(e) => error!("load mdd file {:?} failed: {:?}", &path, e),
+        }
+    }
+
+    // indexing失败时没有db, 查询时直接读mdx
+    let db_file = PathBuf::from(format!("{}{}", file, ".db"));
+    let db = if db_file.exists() {
+        match Connection::open(&db_file) {
+            Ok(conn) => Some(Mutex::new(conn)),
+            Err(e) => {
+                error!("open db file {:?} failed: {:?}", &db_file, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Some(Dictionary {
+        name: dict_name(file),
+        mdx,
+        mdd,
+        db,
+    })
+}
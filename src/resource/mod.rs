@@ -0,0 +1,90 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use regex::{Captures, Regex};
+
+/// 根据扩展名猜测资源的content type
+pub fn content_type(path: &str) -> String {
+    mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+/// 把释义中引用mdd资源的地址改成 /res/{dict}/{path}, 由 handle_resource 从这个词典的mdd中读取
+/// e.g. `<img src="uk_pron.png">` `<link href="O8C.css">` `<a href="sound://us/apple.mp3">`
+/// 查词的链接 `<a href="entry://apple">` `<a href="/apple">` 和外部链接不变
+pub fn rewrite_resource_links(html: &str, dict: &str) -> String {
+    static ATTR: OnceLock<Regex> = OnceLock::new();
+    let attr = ATTR.get_or_init(|| Regex::new(r#"(?i)\b(src|href)(\s*=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap());
+    attr.replace_all(html, |cap: &Captures| {
+        let name = &cap[1];
+        let value = cap.get(3).or_else(|| cap.get(4)).map_or("", |m| m.as_str());
+        match resource_path(name, value) {
+            Some(path) => format!(r#"{}{}"/res/{}/{}""#, name, &cap[2], encode(dict, false), encode(&path, true)),
+            None => cap[0].to_string(),
+        }
+    })
+    .into_owned()
+}
+
+/// 属性值是mdd中的资源时返回资源路径
+/// sound:// 总是资源; 其他的只有相对路径才是, href 还必须有扩展名, 不然是查词的链接
+fn resource_path(attr: &str, value: &str) -> Option<String> {
+    let value = value.trim();
+    let path = match value.strip_prefix("sound://") {
+        Some(path) => path,
+        None => {
+            let is_relative = !value.is_empty()
+                && !value.starts_with(['/', '\\', '#'])
+                && !has_scheme(value);
+            let is_file = attr.eq_ignore_ascii_case("src") || Path::new(value).extension().is_some();
+            if !is_relative || !is_file {
+                return None;
+            }
+            value
+        }
+    };
+    let path = path.trim_start_matches(['/', '\\']).replace('\\', "/");
+    (!path.is_empty()).then_some(path)
+}
+
+/// e.g. "http:" "entry:" "data:" "javascript:"
+fn has_scheme(value: &str) -> bool {
+    match value.find(':') {
+        Some(i) => value[..i].chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)),
+        None => false,
+    }
+}
+
+/// url path 的percent-encoding, keep_slash 为 true 时保留路径中的 `/`
+fn encode(text: &str, keep_slash: bool) -> String {
+    let mut encoded = String::new();
+    for b in text.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || (keep_slash && b == b'/') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_resources_to_dict_route() {
+        let html = r#"<link rel="stylesheet" href="O8C.css"><img src='img\a b.png'><a href="sound://us/apple.mp3">"#;
+        assert_eq!(
+            rewrite_resource_links(html, "牛津 8"),
+            r#"<link rel="stylesheet" href="/res/%E7%89%9B%E6%B4%A5%208/O8C.css"><img src="/res/%E7%89%9B%E6%B4%A5%208/img/a%20b.png"><a href="/res/%E7%89%9B%E6%B4%A5%208/us/apple.mp3">"#
+        );
+    }
+
+    #[test]
+    fn keep_word_and_external_links() {
+        let html = r##"<a href="entry://apple"><a href="/cool"><a href="#top"><a href="cool"><img src="https://a.com/a.png"><img src="data:image/png;base64,AA">"##;
+        assert_eq!(rewrite_resource_links(html, "d"), html);
+    }
+}
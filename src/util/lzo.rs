@@ -0,0 +1,193 @@
+use crate::mdict::error::MdictError;
+
+/// LZO1X 解压, 和 lzo1x_decompress_safe 相同的格式
+/// minilzo-rs 的 decompress_safe 总是返回 max_len 个bytes, 不足的部分补0, 也不能知道实际解压出多少
+/// 这里按实际解压出的数据返回, 输出随解压增长, 最多 max_len 个bytes
+pub fn decompress(src: &[u8], max_len: usize) -> Result<Vec<u8>, MdictError> {
+    let mut lzo = Decoder {
+        src,
+        ip: 0,
+        out: Vec::with_capacity(max_len.min(src.len().saturating_mul(4))),
+        max_len,
+    };
+    lzo.run()?;
+    if lzo.ip != src.len() {
+        return Err(error("input not consumed"));
+    }
+    Ok(lzo.out)
+}
+
+fn error(msg: &str) -> MdictError {
+    MdictError::Decompress(format!("lzo: {}", msg))
+}
+
+struct Decoder<'a> {
+    src: &'a [u8],
+    ip: usize,
+    out: Vec<u8>,
+    max_len: usize,
+}
+
+impl Decoder<'_> {
+    fn byte(&mut self) -> Result<usize, MdictError> {
+        let b = *self.src.get(self.ip).ok_or_else(|| error("input overrun"))?;
+        self.ip += 1;
+        Ok(b as usize)
+    }
+
+    fn le16(&mut self) -> Result<usize, MdictError> {
+        Ok(self.byte()? | (self.byte()? << 8))
+    }
+
+    /// 长度为0时, 后面每个 \x00 表示加255, 直到一个非0的byte
+    fn extended_len(&mut self, base: usize) -> Result<usize, MdictError> {
+        let mut len = base;
+        loop {
+            match self.byte()? {
+                0 => len += 255,
+                b => return Ok(len + b),
+            }
+            if len > self.max_len {
+                return Err(error("output overrun"));
+            }
+        }
+    }
+
+    fn check_output(&self, len: usize) -> Result<(), MdictError> {
+        if self.out.len() + len > self.max_len {
+            return Err(error("output overrun"));
+        }
+        Ok(())
+    }
+
+    fn literals(&mut self, len: usize) -> Result<(), MdictError> {
+        self.check_output(len)?;
+        let bytes = self
+            .src
+            .get(self.ip..self.ip + len)
+            .ok_or_else(|| error("input overrun"))?;
+        self.out.extend_from_slice(bytes);
+        self.ip += len;
+        Ok(())
+    }
+
+    /// 复制之前解压出的数据, distance 从当前位置往前数, 可以和要写入的部分重叠
+    fn copy_match(&mut self, distance: usize, len: usize) -> Result<(), MdictError> {
+        if distance == 0 || distance > self.out.len() {
+            return Err(error("lookbehind overrun"));
+        }
+        self.check_output(len)?;
+        let start = self.out.len() - distance;
+        for i in start..start + len {
+            self.out.push(self.out[i]);
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), MdictError> {
+        // 上一条指令之后复制的literal个数, 决定0..15指令的含义
+        let mut state = 0;
+        if self.src.first().is_some_and(|&b| b > 17) {
+            let len = self.byte()? - 17;
+            self.literals(len)?;
+            state = len.min(4);
+        }
+        loop {
+            let inst = self.byte()?;
+            let (distance, len, next) = if inst >= 64 {
+                // M2: 3..8 bytes, distance 1..2048
+                let distance = ((inst >> 2) & 7) + (self.byte()? << 3) + 1;
+                (distance, (inst >> 5) + 1, inst & 3)
+            } else if inst >= 32 {
+                // M3: distance 1..16384
+                let len = match inst & 31 {
+                    0 => self.extended_len(31)?,
+                    n => n,
+                } + 2;
+                let d = self.le16()?;
+                ((d >> 2) + 1, len, d & 3)
+            } else if inst >= 16 {
+                // M4: distance 16385..49151, distance为0时是结束标记
+                let len = match inst & 7 {
+                    0 => self.extended_len(7)?,
+                    n => n,
+                } + 2;
+                let d = self.le16()?;
+                let distance = ((inst & 8) << 11) + (d >> 2);
+                if distance == 0 {
+                    return Ok(());
+                }
+                (distance + 16384, len, d & 3)
+            } else if state == 0 {
+                // literal run
+                let len = match inst {
+                    0 => self.extended_len(15)?,
+                    n => n,
+                } + 3;
+                self.literals(len)?;
+                state = 4;
+                continue;
+            } else if state < 4 {
+                // M1: 短literal之后的2 bytes match
+                ((inst >> 2) + (self.byte()? << 2) + 1, 2, inst & 3)
+            } else {
+                // M1: 长literal之后的3 bytes match, distance 2049..3072
+                ((inst >> 2) + (self.byte()? << 2) + 2049, 3, inst & 3)
+            };
+            self.copy_match(distance, len)?;
+            self.literals(next)?;
+            state = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        minilzo_rs::LZO::init().unwrap().compress(data).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut random = vec![];
+        let mut x: u32 = 1;
+        for _ in 0..70000 {
+            x = x.wrapping_mul(1103515245).wrapping_add(12345);
+            random.push((x >> 16) as u8);
+        }
+        let cases: Vec<Vec<u8>> = vec![
+            vec![],
+            b"a".to_vec(),
+            b"abc".to_vec(),
+            b"apple apple apple apple banana apple".to_vec(),
+            vec![0; 100000],
+            "苹果 <b>apple</b> 香蕉 ".repeat(3000).into_bytes(),
+            random.clone(),
+            // 超过16KB的distance, 使用M4
+            [&random[..20000], &random[..20000]].concat(),
+        ];
+        for data in cases {
+            let compressed = compress(&data);
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+            // 实际长度小于max_len时不补0
+            assert_eq!(decompress(&compressed, data.len() + 100).unwrap(), data);
+            if !data.is_empty() {
+                assert!(decompress(&compressed, data.len() - 1).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_input() {
+        let compressed = compress(&"apple banana ".repeat(100).into_bytes());
+        assert!(decompress(&compressed[..compressed.len() - 3], 1300).is_err());
+        assert!(decompress(&[compressed.as_slice(), b"x"].concat(), 1300).is_err());
+        assert!(decompress(&[], 10).is_err());
+        // 第一个指令就引用之前的数据
+        assert!(decompress(&[0x40, 0x00, 0x11, 0x00, 0x00], 10).is_err());
+        // literal长度的0可以无限延长, 超过max_len时停止
+        assert!(decompress(&vec![0; 1000], 10).is_err());
+    }
+}
@@ -1,12 +1,27 @@
+use std::io::{Read, Write};
+
+use encoding::all::{GB18030, UTF_8};
+use encoding::label::encoding_from_whatwg_label;
+use encoding::EncodingRef;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use nom::error::{ErrorKind, ParseError};
+use nom::bytes::complete::{take, take_till};
 use nom::number::complete::{be_u16, be_u8};
-use nom::IResult;
+use nom::sequence::terminated;
+use ripemd::{Digest, Ripemd128};
+
+use crate::mdict::error::{MdictError, ParseResult};
+
+mod lzo;
 
 // 解压缩这个地方优化一下
 pub fn fast_decrypt(encrypted: &[u8], key: &[u8]) -> Vec<u8> {
     let mut buf = Vec::from(encrypted);
     let mut prev = 0x36;
     for i in 0..buf.len() {
-        let mut t = buf[i] >> 4 | buf[i] << 4;
+        let mut t = buf[i].rotate_left(4);
         t = t ^ prev ^ (i as u8) ^ key[i % key.len()];
         prev = buf[i];
         buf[i] = t;
@@ -14,12 +29,365 @@ pub fn fast_decrypt(encrypted: &[u8], key: &[u8]) -> Vec<u8> {
     buf
 }
 
+/// fast_decrypt 的逆操作, 写加密的 key block info 时使用
+pub fn fast_encrypt(data: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::from(data);
+    let mut prev = 0x36;
+    for i in 0..buf.len() {
+        let t = buf[i] ^ prev ^ (i as u8) ^ key[i % key.len()];
+        buf[i] = t.rotate_left(4);
+        prev = buf[i];
+    }
+    buf
+}
+
+/// 解密 key block 或 record block 的数据部分
+/// enc_method: 0 不加密, 1 fast_decrypt, 2 Salsa20/8
+/// enc_size: 只有前enc_size个bytes加密, 和readmdict一样只解密 data[:enc_size]
+pub fn decrypt_block(
+    encrypted: &[u8],
+    enc_method: u32,
+    enc_size: usize,
+    key: &[u8],
+) -> Result<Vec<u8>, MdictError> {
+    let (head, tail) = encrypted.split_at(enc_size.min(encrypted.len()));
+    let mut data = match enc_method {
+        0 => return Ok(Vec::from(encrypted)),
+        1 => fast_decrypt(head, key),
+        2 => salsa_decrypt(head, key)?,
+        _ => return Err(MdictError::UnknownEncryption(enc_method)),
+    };
+    data.extend_from_slice(tail);
+    Ok(data)
+}
+
+/// block数据部分的解密key: 有注册码或v3的UUID得到的key时使用它, 否则是block checksum的ripemd128
+pub fn block_key(checksum: &[u8], record_key: Option<&[u8]>) -> Vec<u8> {
+    match record_key {
+        Some(key) => key.to_vec(),
+        None => {
+            let mut md = Ripemd128::new();
+            md.update(checksum);
+            md.finalize().to_vec()
+        }
+    }
+}
+
+/// 解密block的数据部分, 不解压
+/// block: type(u32), checksum, 然后是 csize - 8 bytes 数据
+pub fn decrypt_block_data(block: &[u8], csize: usize, record_key: Option<&[u8]>) -> Result<Vec<u8>, MdictError> {
+    let (Some(head), Some(data)) = (block.get(..8), block.get(8..csize)) else {
+        return Err(MdictError::Truncated);
+    };
+    let enc = u32::from_le_bytes([head[0], head[1], head[2], head[3]]);
+    let key = block_key(&head[4..8], record_key);
+    decrypt_block(data, (enc >> 4) & 0xf, ((enc >> 8) & 0xff) as usize, &key)
+}
+
+/// key block 或 record block 开头的 adler32 checksum, 在压缩类型之后
+pub fn block_checksum(block: &[u8]) -> Option<u32> {
+    let bytes = block.get(4..8)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// block的数据部分的长度, csize包含开头的压缩类型和checksum共8个bytes
+pub fn block_data_size(csize: usize) -> Result<usize, MdictError> {
+    csize
+        .checked_sub(8)
+        .ok_or_else(|| MdictError::Malformed(format!("block size {} is too small", csize)))
+}
+
+/// 一个block解压后大小的上限, 避免文件中错误的dsize导致分配过多内存
+pub const MAX_BLOCK_SIZE: usize = 256 * 1024 * 1024;
+
+/// zlib 的最大压缩比约为 1032:1, lzo 更小, 超过这个比例的dsize一定是错误的
+const MAX_COMPRESSION_RATIO: usize = 1032;
+
+/// 解压 key block 或 record block 的数据部分
+/// comp_method: 0 不压缩, 1 lzo, 2 zlib
+/// dsize: 解压后的大小, 最多解压出dsize个bytes
+/// 不按dsize预先分配内存, 内存随实际解压出的数据增长, 错误的dsize不会导致分配过多内存
+/// 返回的长度可能小于dsize, 由调用方检查
+pub fn decompress_block(data: Vec<u8>, comp_method: u32, dsize: usize) -> Result<Vec<u8>, MdictError> {
+    if comp_method != 0 && (dsize > MAX_BLOCK_SIZE || dsize > data.len().saturating_mul(MAX_COMPRESSION_RATIO)) {
+        return Err(MdictError::Malformed(format!(
+            "block decompressed size {} is too large for {} compressed bytes",
+            dsize,
+            data.len()
+        )));
+    }
+    match comp_method {
+        0 => Ok(data),
+        1 => lzo::decompress(&data, dsize),
+        2 => {
+            let mut v = vec![];
+            ZlibDecoder::new(&data[..])
+                .take(dsize as u64)
+                .read_to_end(&mut v)
+                .map_err(|e| MdictError::Decompress(e.to_string()))?;
+            Ok(v)
+        }
+        _ => Err(MdictError::UnknownCompression(comp_method)),
+    }
+}
+
+/// 压缩 key block 或 record block 的数据部分, decompress_block 的逆操作
+pub fn compress_block(data: &[u8], comp_method: u32) -> Result<Vec<u8>, MdictError> {
+    match comp_method {
+        0 => Ok(Vec::from(data)),
+        1 => {
+            let mut lzo = minilzo_rs::LZO::init().map_err(|e| MdictError::Compress(e.to_string()))?;
+            lzo.compress(data).map_err(|e| MdictError::Compress(e.to_string()))
+        }
+        2 => {
+            let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+            encoder
+                .write_all(data)
+                .and_then(|_| encoder.finish())
+                .map_err(|e| MdictError::Compress(e.to_string()))
+        }
+        _ => Err(MdictError::UnknownCompression(comp_method)),
+    }
+}
+
 /// nom parser
-pub fn text_len_parser_v2(input: &[u8]) -> IResult<&[u8], u16> {
+pub fn text_len_parser_v2(input: &[u8]) -> ParseResult<'_, u32> {
     let (input, len) = be_u16(input)?;
-    Ok((input, len + 1))
+    Ok((input, len as u32 + 1))
+}
+
+/// utf-16 的长度是 code unit 的个数, 结尾是两个字节的 \x00\x00
+pub fn text_len_parser_v2_utf16(input: &[u8]) -> ParseResult<'_, u32> {
+    let (input, len) = be_u16(input)?;
+    Ok((input, (len as u32 + 1) * 2))
+}
+
+pub fn text_len_parser_v1(input: &[u8]) -> ParseResult<'_, u32> {
+    let (input, len) = be_u8(input)?;
+    Ok((input, len as u32))
+}
+
+pub fn text_len_parser_v1_utf16(input: &[u8]) -> ParseResult<'_, u32> {
+    let (input, len) = be_u8(input)?;
+    Ok((input, len as u32 * 2))
+}
+
+/// 读取一个以 \x00 结尾的text, 返回的bytes不包含结尾
+pub fn text_parser(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    terminated(take_till(|x| x == 0), take(1_usize))(input)
+}
+
+/// 读取一个以两个字节 \x00\x00 结尾的utf-16 text, 返回的bytes不包含结尾
+pub fn utf16_text_parser(input: &[u8]) -> ParseResult<'_, &[u8]> {
+    let mut i = 0;
+    while i + 1 < input.len() {
+        if input[i] == 0 && input[i + 1] == 0 {
+            return Ok((&input[i + 2..], &input[..i]));
+        }
+        i += 2;
+    }
+    Err(nom::Err::Error(MdictError::from_error_kind(input, ErrorKind::TakeUntil)))
 }
 
-pub fn text_len_parser_v1(input: &[u8]) -> IResult<&[u8], u8> {
-    be_u8(input)
+pub fn is_utf16(encoding: &str) -> bool {
+    encoding.to_uppercase().starts_with("UTF-16")
+}
+
+/// header中的Encoding对应的decoder
+/// GB2312/GBK的词典里常有超出字符集的字, 统一按GB18030解码; 没有Encoding时默认UTF-8
+pub fn text_decoder(encoding: &str) -> Result<EncodingRef, MdictError> {
+    match encoding.trim().to_uppercase().as_str() {
+        "" => Ok(UTF_8),
+        "GB2312" | "GBK" | "GB18030" => Ok(GB18030),
+        _ => encoding_from_whatwg_label(encoding.trim())
+            .ok_or_else(|| MdictError::BadEncoding(encoding.to_string())),
+    }
+}
+
+/// Salsa20/8, iv全为0. mdict的key是16 bytes(ripemd128), salsa20 crate只支持32 bytes的key
+/// 加密和解密是同一个操作, key 不是16或32 bytes时返回 Malformed
+pub fn salsa_decrypt(encrypted: &[u8], key: &[u8]) -> Result<Vec<u8>, MdictError> {
+    // "expand 16-byte k" / "expand 32-byte k"
+    let (constants, k0, k1) = match key.len() {
+        16 => ([0x61707865, 0x3120646e, 0x79622d36, 0x6b206574], key, key),
+        32 => ([0x61707865, 0x3320646e, 0x79622d32, 0x6b206574], &key[..16], &key[16..]),
+        n => return Err(MdictError::Malformed(format!("invalid salsa20 key length: {}", n))),
+    };
+    let word = |b: &[u8], i: usize| u32::from_le_bytes([b[i * 4], b[i * 4 + 1], b[i * 4 + 2], b[i * 4 + 3]]);
+
+    let mut state = [0u32; 16];
+    state[0] = constants[0];
+    state[5] = constants[1];
+    state[10] = constants[2];
+    state[15] = constants[3];
+    for i in 0..4 {
+        state[1 + i] = word(k0, i);
+        state[11 + i] = word(k1, i);
+    }
+    // state[6..8] iv, state[8..10] block counter
+
+    let mut buf = Vec::from(encrypted);
+    for (counter, chunk) in buf.chunks_mut(64).enumerate() {
+        state[8] = counter as u32;
+        state[9] = (counter as u64 >> 32) as u32;
+        let stream = salsa_block(&state, 8);
+        for (b, s) in chunk.iter_mut().zip(stream.iter()) {
+            *b ^= s;
+        }
+    }
+    Ok(buf)
+}
+
+fn salsa_block(state: &[u32; 16], rounds: usize) -> [u8; 64] {
+    fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    }
+
+    let mut x = *state;
+    for _ in 0..rounds / 2 {
+        // column round
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 5, 9, 13, 1);
+        quarter_round(&mut x, 10, 14, 2, 6);
+        quarter_round(&mut x, 15, 3, 7, 11);
+        // row round
+        quarter_round(&mut x, 0, 1, 2, 3);
+        quarter_round(&mut x, 5, 6, 7, 4);
+        quarter_round(&mut x, 10, 11, 8, 9);
+        quarter_round(&mut x, 15, 12, 13, 14);
+    }
+
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        out[i * 4..i * 4 + 4].copy_from_slice(&x[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    out
+}
+
+/// 测试用: 生成一个 key block 或 record block, 前 enc_size 个bytes使用 enc_method 加密
+#[cfg(test)]
+pub(crate) fn encrypt_block(
+    data: &[u8],
+    comp_method: u32,
+    enc_method: u32,
+    enc_size: usize,
+    record_key: Option<&[u8]>,
+) -> Vec<u8> {
+    let checksum = adler32::adler32(data).unwrap().to_be_bytes();
+    let compressed = compress_block(data, comp_method).unwrap();
+    let key = block_key(&checksum, record_key);
+    let n = enc_size.min(compressed.len());
+    let mut encrypted = match enc_method {
+        1 => fast_encrypt(&compressed[..n], &key),
+        2 => salsa_decrypt(&compressed[..n], &key).unwrap(),
+        _ => compressed[..n].to_vec(),
+    };
+    encrypted.extend_from_slice(&compressed[n..]);
+
+    let mut block = (comp_method | enc_method << 4 | (enc_size as u32) << 8).to_le_bytes().to_vec();
+    block.extend_from_slice(&checksum);
+    block.extend(encrypted);
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn salsa20_8_core_test_vector() {
+        // RFC 7914 8. Test Vectors for Salsa20/8 Core
+        let input = hex("7e879a214f3ec9867ca940e641718f26baee555b8c61c1b50df846116dcd3b1d\
+                         ee24f319df9b3d8514121e4b5ac5aa3276021d2909c74829edebc68db8b8c25e");
+        let output = hex("a41f859c6608cc993b81cacb020cef05044b2181a2fd337dfd7b1c6396682f29\
+                          b4393168e3c9e6bcfe6bc5b7a06d96bae424cc102c91745c24ad673dc7618f81");
+        let mut state = [0u32; 16];
+        for (i, word) in input.chunks(4).enumerate() {
+            state[i] = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        assert_eq!(salsa_block(&state, 8).to_vec(), output);
+    }
+
+    #[test]
+    fn salsa_decrypt_is_its_own_inverse() {
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        for key in [vec![7u8; 16], (0..32).collect()] {
+            let encrypted = salsa_decrypt(&data, &key).unwrap();
+            assert_ne!(encrypted, data);
+            // 每64 bytes换一个block counter, 两个block的key stream不能一样
+            assert_ne!(encrypted[..64], encrypted[64..128]);
+            assert_eq!(salsa_decrypt(&encrypted, &key).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn decrypt_block_round_trip() {
+        let key = (0..16).collect::<Vec<u8>>();
+        let data = b"some compressed block data, longer than the encrypted prefix".to_vec();
+
+        let encrypted = fast_encrypt(&data, &key);
+        assert_eq!(decrypt_block(&encrypted, 1, data.len(), &key).unwrap(), data);
+        let mut encrypted = fast_encrypt(&data[..16], &key);
+        encrypted.extend_from_slice(&data[16..]);
+        assert_eq!(decrypt_block(&encrypted, 1, 16, &key).unwrap(), data);
+
+        let encrypted = salsa_decrypt(&data, &key).unwrap();
+        assert_eq!(decrypt_block(&encrypted, 2, data.len(), &key).unwrap(), data);
+        let mut encrypted = salsa_decrypt(&data[..16], &key).unwrap();
+        encrypted.extend_from_slice(&data[16..]);
+        assert_eq!(decrypt_block(&encrypted, 2, 16, &key).unwrap(), data);
+
+        // enc_size 大于数据长度时整个block都解密
+        let encrypted = fast_encrypt(&data, &key);
+        assert_eq!(decrypt_block(&encrypted, 1, 255, &key).unwrap(), data);
+    }
+
+    #[test]
+    fn salsa_decrypt_rejects_invalid_key_length() {
+        assert!(matches!(salsa_decrypt(b"data", &[0; 15]), Err(MdictError::Malformed(_))));
+        assert!(matches!(decrypt_block(b"data", 2, 4, &[0; 20]), Err(MdictError::Malformed(_))));
+    }
+
+    #[test]
+    fn decrypt_block_enc_size_zero_decrypts_nothing() {
+        // 和readmdict一样, 只解密 data[:enc_size]
+        let key = [1u8; 16];
+        let data = b"plain".to_vec();
+        assert_eq!(decrypt_block(&data, 1, 0, &key).unwrap(), data);
+        assert_eq!(decrypt_block(&data, 2, 0, &key).unwrap(), data);
+        assert_eq!(decrypt_block(&data, 0, 5, &key).unwrap(), data);
+        assert!(matches!(decrypt_block(&data, 3, 5, &key), Err(MdictError::UnknownEncryption(3))));
+    }
+
+    #[test]
+    fn decompress_block_is_bounded_by_output() {
+        let data = b"apple banana cherry".repeat(10);
+        for comp_method in [1, 2] {
+            let compressed = compress_block(&data, comp_method).unwrap();
+            // dsize 比实际大时只返回实际解压出的数据
+            let claimed = compressed.len() * MAX_COMPRESSION_RATIO;
+            assert_eq!(decompress_block(compressed.clone(), comp_method, claimed).unwrap(), data);
+            // 超过压缩比或者block大小上限的dsize直接拒绝
+            let result = decompress_block(compressed.clone(), comp_method, claimed + 1);
+            assert!(matches!(result, Err(MdictError::Malformed(_))), "{}", comp_method);
+            let result = decompress_block(vec![0; MAX_BLOCK_SIZE], comp_method, MAX_BLOCK_SIZE + 1);
+            assert!(matches!(result, Err(MdictError::Malformed(_))), "{}", comp_method);
+        }
+    }
+
+    #[test]
+    fn block_smaller_than_its_header() {
+        for csize in 0..8 {
+            assert!(matches!(block_data_size(csize), Err(MdictError::Malformed(_))));
+            assert!(matches!(decrypt_block_data(&[0; 16], csize, None), Err(MdictError::Truncated)));
+        }
+        assert_eq!(block_data_size(8).unwrap(), 0);
+    }
 }